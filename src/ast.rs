@@ -0,0 +1,167 @@
+//
+// Haymaker
+//
+
+use crate::regexes;
+
+/// Byte offsets into the text a node was parsed from
+pub type Span = (usize, usize);
+
+/// A line of hayfile text, parsed by the shell grammar
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShellNode {
+    Root(Vec<ShellNode>),
+    Text(String),
+    Expand(String, Span), // @var
+    Subcall {
+        parts: Vec<Vec<ShellNode>>, // the initial state, then each command, split on |
+        rederive: bool,             // @@(...) derives its result again
+        span: Span,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Open,         // @(
+    Rederive,     // @@(
+    Close,        // )
+    Pipe,         // |
+    Head(String), // a variable name right after an opening, holding the initial state
+    Var(String),  // @var
+    Text(String),
+}
+
+/// A problem with a line, and where it is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub message: String,
+    pub span: Span,
+}
+
+/// Splits a line into tokens. Parentheses and pipes only mean something inside a subcall,
+/// where plain parentheses nest, and nothing does inside single quotes.
+pub fn lex(text: &str) -> Result<Vec<(usize, Token, usize)>, Error> {
+    let mut tokens = vec![];
+    let mut text_start = 0;
+    let mut plain = String::new();
+
+    let mut opens = vec![]; // where each open subcall starts
+    let mut parens = vec![]; // how many plain parentheses are open in each subcall
+    let mut quoted = false;
+
+    let mut chars = text.char_indices().peekable();
+
+    macro_rules! token {
+        ($start:expr, $token:expr, $end:expr) => {{
+            if !plain.is_empty() {
+                tokens.push((text_start, Token::Text(std::mem::take(&mut plain)), $start));
+            }
+            tokens.push(($start, $token, $end));
+            text_start = $end;
+        }};
+    }
+
+    // opens a subcall, which may start with the name of a variable holding its initial state
+    macro_rules! open {
+        ($offset:expr, $token:expr, $len:expr) => {{
+            for _ in 1..$len {
+                chars.next();
+            }
+            let end = $offset + $len;
+            token!($offset, $token, end);
+            opens.push($offset);
+            parens.push(0);
+
+            if let Some(head) = regexes::VAR_AT.find(&text[end..]) {
+                let head_end = end + head.end();
+                while chars.next_if(|&(next, _)| next < head_end).is_some() {}
+                token!(end, Token::Head(head.as_str().to_string()), head_end);
+            }
+        }};
+    }
+
+    while let Some((offset, c)) = chars.next() {
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        if quoted || c == '\'' {
+            plain.push(c);
+            continue;
+        }
+
+        let rest = &text[offset..];
+        let inside = !opens.is_empty();
+
+        match c {
+            '@' if rest.starts_with("@@(") => open!(offset, Token::Rederive, 3),
+            '@' if rest.starts_with("@(") => open!(offset, Token::Open, 2),
+            '@' => match regexes::VAR_AT_WITH_SIGN.find(rest) {
+                Some(var) => {
+                    let end = offset + var.end();
+                    while chars.next_if(|&(next, _)| next < end).is_some() {}
+                    token!(offset, Token::Var(var.as_str()[1..].to_string()), end);
+                }
+                None => plain.push(c),
+            },
+            '(' if inside => {
+                *parens.last_mut().unwrap() += 1;
+                plain.push(c);
+            }
+            ')' if inside && parens.last() == Some(&0) => {
+                opens.pop();
+                parens.pop();
+                token!(offset, Token::Close, offset + 1);
+            }
+            ')' if inside => {
+                *parens.last_mut().unwrap() -= 1;
+                plain.push(c);
+            }
+            '|' if inside && parens.last() == Some(&0) => token!(offset, Token::Pipe, offset + 1),
+            _ => plain.push(c),
+        }
+    }
+
+    if let Some(&start) = opens.last() {
+        let message = String::from("this subcall is never closed");
+        return Err(Error {
+            message,
+            span: (start, text.len()),
+        });
+    }
+    if !plain.is_empty() {
+        tokens.push((text_start, Token::Text(plain), text.len()));
+    }
+    Ok(tokens)
+}
+
+#[test]
+fn test_lexer() {
+    use Token::*;
+
+    let kinds = |text| {
+        lex(text)
+            .unwrap()
+            .into_iter()
+            .map(|(_, token, _)| token)
+            .collect::<Vec<_>>()
+    };
+    let text = |text: &str| Text(text.to_string());
+
+    assert_eq!(kinds("a | (b)"), vec![text("a | (b)")]);
+    assert_eq!(
+        kinds("@(out | x (a|b)) '@(' @@(@1)"),
+        vec![
+            Open,
+            Head(String::from("out")),
+            text(" "),
+            Pipe,
+            text(" x (a|b)"),
+            Close,
+            text(" '@(' "),
+            Rederive,
+            Var(String::from("1")),
+            Close,
+        ]
+    );
+    assert_eq!(lex("ok @(never").unwrap_err().span, (3, 10));
+}
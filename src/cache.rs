@@ -0,0 +1,451 @@
+//
+// Haymaker
+//
+
+use crate::console::Color;
+use crate::remote::Remote;
+
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// A content-addressed store of recipe outputs, shared by every checkout on the machine.
+/// Action entries under `ac/` map a recipe's key to the hashes of its outputs, whose
+/// contents live under `cas/`. A remote store with the same layout may back it.
+pub struct Cache {
+    root: PathBuf,
+    remote: Option<Remote>,
+    offline: AtomicBool, // set once the remote fails, so it's only reported once
+}
+
+/// What a recipe left behind, as recorded in an action entry
+pub struct Entry {
+    pub outputs: Vec<(String, String, u32)>, // path, hash, and mode of each output
+    pub deps: Vec<(String, String)>, // implicit inputs found by the depfile, and their hashes
+}
+
+pub struct Stats {
+    pub entries: usize,
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+impl Cache {
+    pub fn new(root: PathBuf) -> Self {
+        Cache {
+            root,
+            remote: None,
+            offline: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_remote(mut self, remote: Remote) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// `$HAYMAKER_CACHE`, or else the user's cache directory
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("HAYMAKER_CACHE") {
+            return PathBuf::from(dir);
+        }
+        if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+            return PathBuf::from(dir).join("haymaker");
+        }
+        match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".cache").join("haymaker"),
+            None => PathBuf::from(".haymaker").join("cache"),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.root
+    }
+
+    fn action(&self, key: &str) -> PathBuf {
+        self.root.join("ac").join(key)
+    }
+
+    fn blob(&self, hash: &str) -> PathBuf {
+        self.root.join("cas").join(hash)
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<Entry> {
+        let path = self.action(key);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => self.fetch(key)?,
+        };
+        let entry = Entry::parse(&text)?;
+
+        // keep recently used entries alive through garbage collection
+        let _ = std::fs::File::open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+        Some(entry)
+    }
+
+    /// Downloads an action entry and the contents it names from the remote store
+    fn fetch(&self, key: &str) -> Option<String> {
+        let remote = self.online()?;
+        let bytes = self.check(remote.get("ac", key))??;
+        let text = String::from_utf8(bytes).ok()?;
+        let entry = Entry::parse(&text)?;
+
+        for (_, hash, _) in &entry.outputs {
+            if self.contents(hash).is_some() {
+                continue;
+            }
+            let bytes = self.check(remote.get("cas", hash))??;
+            if &hash_bytes(&bytes) != hash {
+                return None; // never trust a corrupted store
+            }
+            self.check(write_atomically(&self.blob(hash), &bytes))?;
+        }
+
+        self.check(write_atomically(&self.action(key), text.as_bytes()))?;
+        Some(text)
+    }
+
+    /// Sends an entry and its contents to the remote store, unless it's read-only
+    fn upload(&self, key: &str, entry: &Entry) {
+        let remote = match self.online() {
+            Some(remote) if !remote.read_only => remote,
+            _ => return,
+        };
+
+        for (_, hash, _) in &entry.outputs {
+            let bytes = match std::fs::read(self.blob(hash)) {
+                Ok(bytes) => bytes,
+                Err(_) => return,
+            };
+            if self.check(remote.put("cas", hash, &bytes)).is_none() {
+                return;
+            }
+        }
+        self.check(remote.put("ac", key, entry.to_string().as_bytes()));
+    }
+
+    fn online(&self) -> Option<&Remote> {
+        match self.offline.load(Ordering::Relaxed) {
+            true => None,
+            false => self.remote.as_ref(),
+        }
+    }
+
+    /// Reports the first remote failure, after which the build continues without it
+    fn check<T>(&self, result: std::io::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                if !self.offline.swap(true, Ordering::Relaxed) {
+                    println!("{}: remote cache unavailable: {}", "Cache".yellow(), err);
+                }
+                None
+            }
+        }
+    }
+
+    /// The contents stored under a hash, as long as they still match it
+    fn contents(&self, hash: &str) -> Option<Vec<u8>> {
+        let bytes = std::fs::read(self.blob(hash)).ok()?;
+        (hash_bytes(&bytes) == hash).then_some(bytes)
+    }
+
+    /// Copies a cached recipe's outputs into place, returning false if any are missing.
+    /// Entries may come from a remote, so they're only trusted to write the recipe's own
+    /// outputs, and only with contents matching their hashes.
+    pub fn restore(&self, entry: &Entry, outputs: &[String]) -> bool {
+        let mut paths = entry.outputs.iter().map(|(path, ..)| path).collect::<Vec<_>>();
+        let mut declared = outputs.iter().collect::<Vec<_>>();
+        paths.sort();
+        declared.sort();
+        if paths != declared {
+            return false;
+        }
+
+        let mut restored = vec![];
+        for output in outputs {
+            let (_, hash, mode) = entry.outputs.iter().find(|(path, ..)| path == output).unwrap();
+            match self.contents(hash) {
+                Some(bytes) => restored.push((output, bytes, *mode)),
+                None => return false,
+            }
+        }
+
+        for (output, bytes, mode) in restored {
+            if let Some(parent) = Path::new(output).parent() {
+                if parent != Path::new("") && std::fs::create_dir_all(parent).is_err() {
+                    return false;
+                }
+            }
+
+            let _ = std::fs::remove_file(output);
+            if std::fs::write(output, bytes).is_err() {
+                return false;
+            }
+            let _ = std::fs::set_permissions(output, std::fs::Permissions::from_mode(mode));
+        }
+        true
+    }
+
+    /// Records a recipe's outputs. Only recipes whose outputs are all regular files can be cached.
+    pub fn store(&self, key: &str, outputs: &[String], deps: &[String]) -> std::io::Result<bool> {
+        let deps = deps.iter().map(|dep| (dep.clone(), hash_file(dep)));
+        let mut entry = Entry {
+            outputs: vec![],
+            deps: deps.collect(),
+        };
+
+        for output in outputs {
+            let meta = match std::fs::metadata(output) {
+                Ok(meta) if meta.is_file() => meta,
+                _ => return Ok(false),
+            };
+            let bytes = std::fs::read(output)?;
+            let hash = hash_bytes(&bytes);
+
+            let blob = self.blob(&hash);
+            if !blob.exists() {
+                write_atomically(&blob, &bytes)?;
+            }
+
+            let mode = meta.permissions().mode() & 0o777;
+            entry.outputs.push((output.clone(), hash, mode));
+        }
+
+        write_atomically(&self.action(key), entry.to_string().as_bytes())?;
+        self.upload(key, &entry);
+        Ok(true)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let entries = listing(&self.root.join("ac"));
+        let blobs = listing(&self.root.join("cas"));
+
+        let sizes = entries.iter().chain(&blobs).map(|(_, meta)| meta.len());
+        Stats {
+            entries: entries.len(),
+            blobs: blobs.len(),
+            bytes: sizes.sum(),
+        }
+    }
+
+    /// Removes entries unused for longer than `max_age`, then the oldest entries until the
+    /// cache fits in `max_size`, and finally any contents no entry refers to.
+    /// Returns the number of files removed.
+    pub fn gc(&self, max_age: Duration, max_size: Option<u64>) -> usize {
+        let now = SystemTime::now();
+        let mut removed = 0;
+
+        let mut entries = listing(&self.root.join("ac"));
+        entries.sort_by_key(|(_, meta)| std::cmp::Reverse(meta.modified().unwrap_or(now)));
+
+        let mut keep = vec![];
+        for (path, meta) in entries {
+            let age = now.duration_since(meta.modified().unwrap_or(now)).unwrap_or_default();
+
+            if age > max_age {
+                removed += std::fs::remove_file(&path).is_ok() as usize;
+                continue;
+            }
+
+            let text = std::fs::read_to_string(&path).unwrap_or_default();
+            match Entry::parse(&text) {
+                Some(entry) => keep.push((path, meta.len(), entry)),
+                None => removed += std::fs::remove_file(&path).is_ok() as usize,
+            }
+        }
+
+        let blobs = listing(&self.root.join("cas"));
+        let size_of = |hash: &str| {
+            let found = blobs.iter().find(|(path, _)| path.file_name().unwrap() == hash);
+            found.map(|(_, meta)| meta.len()).unwrap_or(0)
+        };
+
+        if let Some(max_size) = max_size {
+            // the newest entries come first, so evict from the back
+            let mut total: u64 = keep.iter().map(|(_, len, _)| len).sum();
+            let mut counted = std::collections::HashSet::new();
+            for (_, _, entry) in &keep {
+                for (_, hash, _) in &entry.outputs {
+                    if counted.insert(hash.clone()) {
+                        total += size_of(hash);
+                    }
+                }
+            }
+
+            while total > max_size {
+                let (path, len, entry) = match keep.pop() {
+                    Some(last) => last,
+                    None => break,
+                };
+                total -= len;
+                for (_, hash, _) in &entry.outputs {
+                    let shared = keep.iter().flat_map(|(_, _, e)| &e.outputs).any(|x| &x.1 == hash);
+                    if !shared && counted.remove(hash) {
+                        total -= size_of(hash);
+                    }
+                }
+                removed += std::fs::remove_file(path).is_ok() as usize;
+            }
+        }
+
+        for (path, _) in &blobs {
+            let hash = path.file_name().unwrap().to_string_lossy();
+            let used = keep.iter().flat_map(|(_, _, e)| &e.outputs).any(|x| x.1 == hash);
+            if !used {
+                removed += std::fs::remove_file(path).is_ok() as usize;
+            }
+        }
+        removed
+    }
+}
+
+impl Entry {
+    /// Whether the implicit inputs still match. These aren't part of the key, since a fresh
+    /// checkout can't know them until the recipe has run once.
+    pub fn current(&self) -> bool {
+        self.deps.iter().all(|(path, hash)| &hash_file(path) == hash)
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut entry = Entry {
+            outputs: vec![],
+            deps: vec![],
+        };
+
+        for line in text.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields.as_slice() {
+                [_, hash, ..] if !valid_hash(hash) => return None,
+                ["out", hash, mode, path] => {
+                    let mode = u32::from_str_radix(mode, 8).ok()?;
+                    entry.outputs.push((path.to_string(), hash.to_string(), mode));
+                }
+                ["dep", hash, path] => entry.deps.push((path.to_string(), hash.to_string())),
+                _ => return None,
+            }
+        }
+        Some(entry)
+    }
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (path, hash, mode) in &self.outputs {
+            writeln!(f, "out\t{}\t{:o}\t{}", hash, mode, path)?;
+        }
+        for (path, hash) in &self.deps {
+            writeln!(f, "dep\t{}\t{}", hash, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a hash is one of ours, so that it's safe to use as a file name
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hashes a file's contents, or its name when it doesn't exist, as with phony inputs
+pub fn hash_file(path: &str) -> String {
+    match std::fs::read(path) {
+        Ok(bytes) => hash_bytes(&bytes),
+        Err(_) => hash_bytes(format!("missing {}", path).as_bytes()),
+    }
+}
+
+/// Parses sizes like 512, 100K, 20M, or 2G
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (digits, scale) = match text.char_indices().last() {
+        Some((offset, 'K' | 'k')) => (&text[..offset], 1 << 10),
+        Some((offset, 'M' | 'm')) => (&text[..offset], 1 << 20),
+        Some((offset, 'G' | 'g')) => (&text[..offset], 1 << 30),
+        _ => (text, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(size) => Ok(size * scale),
+        Err(_) => Err(format!("{} is not a size", text)),
+    }
+}
+
+/// Writes through a temporary file so that other checkouts never see partial contents
+fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir)?;
+
+    let name = path.file_name().unwrap().to_string_lossy();
+    let temp = dir.join(format!(".{}.{}", name, std::process::id()));
+    std::fs::write(&temp, bytes)?;
+    std::fs::rename(temp, path)
+}
+
+fn listing(dir: &Path) -> Vec<(PathBuf, std::fs::Metadata)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let entries = entries
+        .flatten()
+        .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)));
+    entries.filter(|(_, meta)| meta.is_file()).collect()
+}
+
+#[test]
+fn test_cache() {
+    let root = std::env::temp_dir().join(format!("haymaker-cache-{}", std::process::id()));
+    let work = root.join("work");
+    std::fs::create_dir_all(&work).unwrap();
+
+    let cache = Cache::new(root.join("cache"));
+    let output = work.join("out.txt").to_string_lossy().to_string();
+    let outputs = vec![output.clone()];
+
+    std::fs::write(&output, "contents").unwrap();
+    assert!(cache.store("key", &outputs, &[String::from("dep.h")]).unwrap());
+    std::fs::remove_file(&output).unwrap();
+
+    let entry = cache.lookup("key").unwrap();
+    assert_eq!(entry.deps[0].0, "dep.h");
+    assert!(entry.current());
+    assert!(!cache.restore(&entry, &[String::from("elsewhere.txt")]));
+    assert!(cache.restore(&entry, &outputs));
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "contents");
+    assert!(cache.lookup("other").is_none());
+
+    // entries can't name files outside the store, and tampered contents aren't restored
+    let escape = format!("out\t../../../etc/passwd\t644\t{}\n", output);
+    assert!(Entry::parse(&escape).is_none());
+    std::fs::write(cache.blob(&entry.outputs[0].1), "tampered").unwrap();
+    assert!(!cache.restore(&entry, &outputs));
+
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.blobs), (1, 1));
+
+    assert_eq!(cache.gc(Duration::from_secs(3600), None), 0);
+    assert_eq!(cache.gc(Duration::from_secs(3600), Some(0)), 2);
+    assert_eq!(cache.stats().entries, 0);
+
+    assert_eq!(parse_size("20M"), Ok(20 << 20));
+    assert!(parse_size("lots").is_err());
+
+    let url = crate::remote::serve();
+    let shared = Cache::new(root.join("shared")).with_remote(Remote::new(&url, false).unwrap());
+    let other = Cache::new(root.join("other")).with_remote(Remote::new(&url, true).unwrap());
+
+    std::fs::write(&output, "shared contents").unwrap();
+    assert!(shared.store("key", &outputs, &[]).unwrap());
+    std::fs::remove_file(&output).unwrap();
+
+    let entry = other.lookup("key").unwrap();
+    assert!(other.restore(&entry, &outputs));
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "shared contents");
+
+    std::fs::remove_dir_all(root).unwrap();
+}
@@ -13,10 +13,19 @@ pub fn uncomment(text: &str, blank: &str) -> Vec<String> {
     for source_line in lines {
         let mut line = String::with_capacity(source_line.len());
         let mut ignore_scope_changes = false;
+        let mut quoted = false; // single quotes keep what they hold, such as patterns like 'a/*.hay'
+        let mut quotes = source_line.matches('\'').count(); // those left, so apostrophes don't open
         let mut chars = source_line.chars().chain(std::iter::once(' ')).tuple_windows();
 
         while let Some((c, n)) = chars.next() {
-            if !ignore_scope_changes {
+            if c == '\'' {
+                quotes -= 1;
+                if scopes == 0 && (quoted || quotes > 0) {
+                    quoted = !quoted;
+                }
+            }
+
+            if !ignore_scope_changes && !quoted {
                 if c == '/' && n == '*' {
                     scopes += 1;
                     line = line + blank + blank;
@@ -24,17 +33,15 @@ pub fn uncomment(text: &str, blank: &str) -> Vec<String> {
                     continue;
                 }
 
-                if c == '*' && n == '/' {
-                    if scopes != 0 {
-                        scopes -= 1;
-                        line = line + blank + blank;
-                        chars.next();
-                        continue;
-                    }
+                if c == '*' && n == '/' && scopes != 0 {
+                    scopes -= 1;
+                    line = line + blank + blank;
+                    chars.next();
+                    continue;
                 }
             }
 
-            if c == '#' || (c == '/' && n == '/') {
+            if !quoted && (c == '#' || (c == '/' && n == '/')) {
                 match scopes {
                     0 => break,
                     _ => ignore_scope_changes = true,
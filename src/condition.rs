@@ -0,0 +1,174 @@
+//
+// Haymaker
+//
+
+use crate::ast::Error;
+use crate::derive::{derive, VarMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+}
+
+/// Derives a condition, which holds when its text isn't empty,
+/// or when its sides compare as asked with == or !=
+pub fn evaluate(text: &str, vars: &mut VarMap, debug: bool) -> Result<bool, Error> {
+    let (left, comparison) = split(text);
+
+    // errors are relative to each side, so move them back into the whole condition
+    let mut side = |start: usize, side: &str| {
+        let derived = derive(side, vars, debug).map_err(|err| Error {
+            span: (start + err.span.0, start + err.span.1),
+            ..err
+        })?;
+        Ok(derived.trim().to_string())
+    };
+
+    let left = side(0, left)?;
+    match comparison {
+        None => Ok(!left.is_empty()),
+        Some((comparison, start, right)) => {
+            let right = side(start, right)?;
+            Ok((left == right) == (comparison == Comparison::Equal))
+        }
+    }
+}
+
+/// Splits a condition on its first comparison outside of quotes and subcalls
+fn split(text: &str) -> (&str, Option<(Comparison, usize, &str)>) {
+    let mut quoted = false;
+    let mut depth = 0;
+    let mut previous = ' ';
+
+    for (offset, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            _ if quoted => {}
+            '(' if depth > 0 || previous == '@' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth > 0 => {}
+            '=' | '!' if text[offset + 1..].starts_with('=') => {
+                let comparison = match c {
+                    '=' => Comparison::Equal,
+                    _ => Comparison::NotEqual,
+                };
+                let right = offset + 2;
+                return (&text[..offset], Some((comparison, right, &text[right..])));
+            }
+            _ => {}
+        }
+        previous = c;
+    }
+    (text, None)
+}
+
+/// The if blocks enclosing the current line, and which of their branches were taken
+#[derive(Default)]
+pub struct Conditionals {
+    open: Vec<Branch>,
+}
+
+struct Branch {
+    enclosing: bool,               // whether the lines around the block are kept
+    active: bool,                  // whether the lines in the current branch are kept
+    taken: bool,                   // whether any branch so far was
+    otherwise: bool,               // whether the final else was reached
+    start: (String, usize, usize), // the if's line, number and column, for errors
+}
+
+impl Conditionals {
+    /// Whether lines here are kept
+    pub fn active(&self) -> bool {
+        self.open.last().is_none_or(|branch| branch.active)
+    }
+
+    /// Whether an else if here needs its condition evaluated
+    pub fn undecided(&self) -> bool {
+        self.open
+            .last()
+            .is_none_or(|branch| branch.enclosing && !branch.taken)
+    }
+
+    pub fn open(&mut self, holds: bool, start: (&str, usize, usize)) {
+        let enclosing = self.active();
+        let start = (start.0.to_string(), start.1, start.2);
+        self.open.push(Branch {
+            enclosing,
+            active: enclosing && holds,
+            taken: enclosing && holds,
+            otherwise: false,
+            start,
+        });
+    }
+
+    /// Moves to the next branch, which is an else if given a condition, or the final else
+    pub fn otherwise(&mut self, holds: Option<bool>) -> Result<(), String> {
+        let branch = match self.open.last_mut() {
+            Some(branch) => branch,
+            None => return Err(String::from("else without an if")),
+        };
+        if branch.otherwise {
+            return Err(String::from("no branch may follow the final else"));
+        }
+
+        branch.active = branch.enclosing && !branch.taken && holds.unwrap_or(true);
+        branch.taken |= branch.active;
+        branch.otherwise = holds.is_none();
+        Ok(())
+    }
+
+    pub fn end(&mut self) -> Result<(), String> {
+        match self.open.pop() {
+            Some(_) => Ok(()),
+            None => Err(String::from("end without an if")),
+        }
+    }
+
+    /// The first if left open at the end of the hayfile
+    pub fn unclosed(&self) -> Option<&(String, usize, usize)> {
+        self.open.first().map(|branch| &branch.start)
+    }
+}
+
+#[test]
+fn test_condition() {
+    use crate::derive::Var;
+
+    let mut vars = VarMap::new();
+    vars.insert(String::from("os"), Var::from(String::from("linux")));
+
+    #[rustfmt::skip]
+    let cases = [
+        ("@os", true),
+        ("@missing", false),
+        ("@os == linux", true),
+        ("@os != linux", false),
+        ("@(os | add ==) == linux ==", true),
+        ("'a == b'", true),
+        (" == ", true),
+    ];
+    for (case, holds) in cases {
+        assert_eq!(evaluate(case, &mut vars, false), Ok(holds), "{}", case);
+    }
+    assert_eq!(
+        evaluate("a == @(x | drop q)", &mut vars, false).unwrap_err().span,
+        (5, 18)
+    );
+
+    let mut conditionals = Conditionals::default();
+    let start = ("", 0, 0);
+    conditionals.open(false, start);
+    assert!(!conditionals.active());
+    conditionals.open(true, start);
+    assert!(!conditionals.active());
+    assert!(conditionals.end().is_ok());
+    assert!(conditionals.undecided());
+    assert!(conditionals.otherwise(Some(true)).is_ok());
+    assert!(conditionals.active());
+    assert!(conditionals.otherwise(None).is_ok());
+    assert!(!conditionals.active());
+    assert!(conditionals.otherwise(None).is_err());
+    assert!(conditionals.end().is_ok());
+    assert!(conditionals.end().is_err());
+}
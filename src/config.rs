@@ -0,0 +1,62 @@
+//
+// Haymaker
+//
+
+use std::path::PathBuf;
+
+/// Per-user settings, read from `~/.config/haymaker/config` as `key = value` lines
+#[derive(Default)]
+pub struct Config {
+    pub remote_cache: Option<String>, // url of an http content store
+    pub remote_read_only: bool,       // fetch from the store but never upload to it
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let path = match Config::path() {
+            Some(path) => path,
+            None => return Config::default(),
+        };
+        match std::fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(_) => Config::default(),
+        }
+    }
+
+    fn path() -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+            return Some(PathBuf::from(dir).join("haymaker").join("config"));
+        }
+        let home = std::env::var_os("HOME")?;
+        Some(PathBuf::from(home).join(".config").join("haymaker").join("config"))
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut config = Config::default();
+
+        for line in text.lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "remote-cache" => config.remote_cache = Some(value.to_owned()),
+                "remote-cache-read-only" => config.remote_read_only = value == "true",
+                _ => continue,
+            }
+        }
+        config
+    }
+}
+
+#[test]
+fn test_config() {
+    let text =
+        "# shared with ci\nremote-cache = http://cache:9090\nremote-cache-read-only = true\n";
+    let config = Config::parse(text);
+    assert_eq!(config.remote_cache.as_deref(), Some("http://cache:9090"));
+    assert!(config.remote_read_only);
+}
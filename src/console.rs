@@ -4,15 +4,15 @@
 
 use crate::text::Text;
 
-pub const BLUE: &'static str = "\x1b[34;1m";
-pub const DIM: &'static str = "\x1b[2m";
-pub const GREY: &'static str = "\x1b[0;0m\x1b[90m";
-pub const MINT: &'static str = "\x1b[38;5;48;1m";
-pub const PINK: &'static str = "\x1b[38;5;161;1m";
-pub const RED: &'static str = "\x1b[31;1m";
-pub const CLEAR: &'static str = "\x1b[0;0m";
-pub const WHITE: &'static str = "\x1b[0;1m";
-pub const YELLOW: &'static str = "\x1b[33;1m";
+pub const BLUE: &str = "\x1b[34;1m";
+pub const DIM: &str = "\x1b[2m";
+pub const GREY: &str = "\x1b[0;0m\x1b[90m";
+pub const MINT: &str = "\x1b[38;5;48;1m";
+pub const PINK: &str = "\x1b[38;5;161;1m";
+pub const RED: &str = "\x1b[31;1m";
+pub const CLEAR: &str = "\x1b[0;0m";
+pub const WHITE: &str = "\x1b[0;1m";
+pub const YELLOW: &str = "\x1b[33;1m";
 
 pub trait Color {
     fn color(&self, color: &str) -> String;
@@ -46,14 +46,21 @@ impl<T> Color for T where T: std::fmt::Display {
     fn yellow(&self) -> String { self.color(YELLOW) }
 }
 
-pub fn print_source_error(
+/// Renders an error about a whole file, such as a hayfile that can't be read
+pub fn file_error(kind: &str, message: &str, filename: &str) -> String {
+    let arrow = "╔═══════".blue();
+    format!("\n{}: {}\n  {} {}\n\n", kind.red(), message, arrow, filename.blue())
+}
+
+/// Renders an error pointing at a column of a line in the hayfile
+pub fn source_error(
     kind: &str,
     message: &str,
     filename: &str,
     line: &str,
     num: usize,
     mut column: usize,
-) {
+) -> String {
     let len = num.to_string().len();
     let margin = " ".repeat(len);
     let pipe = "║".blue();
@@ -69,16 +76,17 @@ pub fn print_source_error(
         column -= white;
     }
 
-    println!();
-    println!("{}: {}", kind.red(), message);
-    println!("{} {} {} {} {}", margin, arrow, filename.blue(), position, editor);
-    println!("{} {}", margin, pipe);
-    println!("{} {} {}", num.blue(), pipe, line);
-    println!("{} {} {}{}", margin, pipe, " ".repeat(column), "^".red());
-    println!();
+    let mut text = String::from("\n");
+    text += &format!("{}: {}\n", kind.red(), message);
+    text += &format!("{} {} {} {} {}\n", margin, arrow, filename.blue(), position, editor);
+    text += &format!("{} {}\n", margin, pipe);
+    text += &format!("{} {} {}\n", num.blue(), pipe, line);
+    text += &format!("{} {} {}{}\n", margin, pipe, " ".repeat(column), "^".red());
+    text + "\n"
 }
 
-pub fn print_processed_error(
+/// Renders an error in a line as it was derived, with notes on where it came from
+pub fn processed_error(
     kind: &str,
     message: &str,
     filename: &str,
@@ -86,7 +94,7 @@ pub fn print_processed_error(
     info: Vec<String>,
     num: usize,
     column: usize,
-) {
+) -> String {
     let len = num.to_string().len();
     let margin = " ".repeat(len);
     let pipe = "║".blue();
@@ -97,17 +105,17 @@ pub fn print_processed_error(
 
     let line = line.replace('\t', " ");
 
-    println!();
-    println!("{}: {}", kind.red(), message);
-    println!("{} {} {} {} {}", margin, arrow, filename.blue(), position, editor);
-    println!("{} {}", margin, pipe);
-    println!("{} {} {}", num.blue(), pipe, line);
-    println!("{} {} {}{}", margin, pipe, " ".repeat(column), "^".red());
-    println!("{} {}", margin, pipe);
+    let mut text = String::from("\n");
+    text += &format!("{}: {}\n", kind.red(), message);
+    text += &format!("{} {} {} {} {}\n", margin, arrow, filename.blue(), position, editor);
+    text += &format!("{} {}\n", margin, pipe);
+    text += &format!("{} {} {}\n", num.blue(), pipe, line);
+    text += &format!("{} {} {}{}\n", margin, pipe, " ".repeat(column), "^".red());
+    text += &format!("{} {}\n", margin, pipe);
 
     for line in info {
         let line = line.replace('\t', " ");
-        println!("{} {} {}", margin, pipe, line);
+        text += &format!("{} {} {}\n", margin, pipe, line);
     }
-    println!();
+    text + "\n"
 }
@@ -0,0 +1,383 @@
+//
+// Haymaker
+//
+
+use crate::console::Color;
+use crate::derive::{Assignment, Var};
+use crate::parsed::Rule;
+use crate::recipe::Recipe;
+use crate::{load, Hayfile};
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::SystemTime;
+
+/// A loaded hayfile and when each of its files was last modified
+struct Loaded {
+    hayfile: Hayfile,
+    warnings: String, // rendered problems that don't stop the build, sent with each answer
+    stamps: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Loaded {
+    fn new(hayfile: Hayfile, warnings: String) -> Self {
+        let stamps = hayfile
+            .files
+            .iter()
+            .map(|file| (file.clone(), modified(file)))
+            .collect();
+        Loaded {
+            hayfile,
+            warnings,
+            stamps,
+        }
+    }
+
+    fn current(&self) -> bool {
+        self.stamps.iter().all(|(file, stamp)| &modified(file) == stamp)
+    }
+}
+
+fn socket(dir: &Path) -> PathBuf {
+    dir.join("daemon.sock")
+}
+
+/// Starts a server in the background, detached from the terminal's signals
+pub fn start(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    if UnixStream::connect(socket(dir)).is_ok() {
+        println!("the daemon is already running");
+        return Ok(());
+    }
+
+    Command::new(std::env::current_exe()?)
+        .args(["daemon", "serve"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    println!("{} the daemon", "started".mint());
+    Ok(())
+}
+
+pub fn stop(dir: &Path) -> std::io::Result<()> {
+    let mut stream = UnixStream::connect(socket(dir))?;
+    stream.write_all(b"stop\n")?;
+    println!("{} the daemon", "stopped".mint());
+    Ok(())
+}
+
+/// Keeps hayfiles loaded in memory, reloading them only when they or their includes change
+pub fn serve(dir: &Path) -> std::io::Result<()> {
+    let path = socket(dir);
+    std::fs::create_dir_all(dir)?;
+    let _ = std::fs::remove_file(&path);
+
+    let listener = UnixListener::bind(&path)?;
+    let mut cache: HashMap<PathBuf, Loaded> = HashMap::new();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        let mut request = String::new();
+        if BufReader::new(&stream).read_line(&mut request).is_err() {
+            continue;
+        }
+        let request = request.trim_end_matches('\n');
+
+        let hayfile = match request.split_once('\t') {
+            Some(("load", hayfile)) => PathBuf::from(hayfile),
+            _ if request == "stop" => break,
+            _ => continue,
+        };
+
+        let text = answer(&mut cache, &hayfile);
+        let _ = (&stream).write_all(text.as_bytes());
+    }
+
+    std::fs::remove_file(path)
+}
+
+/// The reply to a request for a hayfile, loading it again if it's changed.
+/// Hayfiles that fail to load are answered with their errors, and the rest with any warnings.
+fn answer(cache: &mut HashMap<PathBuf, Loaded>, hayfile: &Path) -> String {
+    let stale = cache.get(hayfile).map(|loaded| !loaded.current()).unwrap_or(true);
+    if stale {
+        let (loaded, mut diagnostics) = load(hayfile, &Default::default());
+        if diagnostics.errors() > 0 {
+            cache.remove(hayfile);
+            return format!("error\t{}\n", escape(&diagnostics.render()));
+        }
+        cache.insert(hayfile.to_path_buf(), Loaded::new(loaded, diagnostics.render()));
+    }
+
+    let loaded = &cache[hayfile];
+    match loaded.warnings.is_empty() {
+        true => encode(&loaded.hayfile),
+        false => format!("warn\t{}\n{}", escape(&loaded.warnings), encode(&loaded.hayfile)),
+    }
+}
+
+/// Asks a running daemon for a hayfile and its warnings, or the errors found loading it,
+/// returning None when there's no daemon to ask
+pub fn request(dir: &Path, hayfile: &Path) -> Option<Result<(Hayfile, String), String>> {
+    let mut stream = match UnixStream::connect(socket(dir)) {
+        Ok(stream) => stream,
+        Err(_) => return None,
+    };
+
+    let request = format!("load\t{}\n", hayfile.to_string_lossy());
+    stream.write_all(request.as_bytes()).ok()?;
+
+    let mut text = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut text).ok()?;
+    reply(&text)
+}
+
+fn reply(text: &str) -> Option<Result<(Hayfile, String), String>> {
+    if let Some(errors) = text.strip_prefix("error\t") {
+        return Some(Err(unescape(errors.trim_end_matches('\n'))));
+    }
+    let warned = text.strip_prefix("warn\t").and_then(|text| text.split_once('\n'));
+    let (warnings, text) = match warned {
+        Some((warnings, text)) => (unescape(warnings), text),
+        None => (String::new(), text),
+    };
+    decode(text).map(|hayfile| Ok((hayfile, warnings)))
+}
+
+fn encode(hayfile: &Hayfile) -> String {
+    let mut text = String::new();
+
+    let mut line = |fields: &[&str]| {
+        text += &fields
+            .iter()
+            .map(|field| escape(field))
+            .collect::<Vec<_>>()
+            .join("\t");
+        text += "\n";
+    };
+
+    for file in &hayfile.files {
+        line(&["file", &file.to_string_lossy()]);
+    }
+    let mut vars: Vec<_> = hayfile.vars.iter().collect();
+    vars.sort_by_key(|(name, _)| name.as_str());
+    for (name, var) in vars {
+        let flavor = var.flavor.to_string();
+        let params: Vec<&str> = var.params.iter().map(|x| x.as_str()).collect();
+        line(&[&["var", name, &flavor, &var.value], params.as_slice()].concat());
+    }
+    for (name, depth) in &hayfile.pools {
+        line(&["pool", name, &depth.to_string()]);
+    }
+
+    for recipe in &hayfile.recipes {
+        let outputs: Vec<&str> = recipe.rule.outputs.iter().map(|x| x.as_str()).collect();
+        line(&[&["rule"], outputs.as_slice()].concat());
+
+        for step in &recipe.rule.steps {
+            let inputs: Vec<&str> = step.iter().map(|x| x.as_str()).collect();
+            line(&[&["step"], inputs.as_slice()].concat());
+        }
+
+        let order: Vec<&str> = recipe.rule.order.iter().map(|x| x.as_str()).collect();
+        line(&[&["order"], order.as_slice()].concat());
+
+        for (name, value) in recipe.attributes() {
+            line(&["attr", name, &value]);
+        }
+        for var in &recipe.vars {
+            let propagate = match var.propagate {
+                true => "propagate",
+                false => "",
+            };
+            line(&[
+                "tvar",
+                propagate,
+                &var.name,
+                &var.flavor.to_string(),
+                &var.value,
+            ]);
+        }
+        for command in &recipe.commands {
+            let debug = match command.debug {
+                true => "+",
+                false => "",
+            };
+            line(&["cmd", debug, &command.line]);
+        }
+    }
+    text += "end\n";
+    text
+}
+
+fn decode(text: &str) -> Option<Hayfile> {
+    let mut hayfile = Hayfile {
+        recipes: vec![],
+        vars: Default::default(),
+        pools: Default::default(),
+        files: vec![],
+    };
+    let mut finished = false;
+
+    for line in text.lines() {
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        let recipe = hayfile.recipes.last_mut();
+
+        match (fields[0].as_str(), recipe) {
+            ("file", _) => hayfile.files.push(PathBuf::from(fields.get(1)?)),
+            ("var", _) => {
+                let mut var = Var::new(fields.get(3)?.clone(), fields.get(2)?.parse().ok()?);
+                var.params = fields[4..].to_vec();
+                hayfile.vars.insert(fields.get(1)?.clone(), var);
+            }
+            ("pool", _) => {
+                let depth = fields.get(2)?.parse().ok()?;
+                hayfile.pools.insert(fields.get(1)?.clone(), depth);
+            }
+            ("rule", _) => {
+                let rule = Rule {
+                    outputs: fields[1..].to_vec(),
+                    steps: vec![],
+                    order: vec![],
+                };
+                hayfile.recipes.push(Recipe::from(rule));
+            }
+            ("step", Some(recipe)) => recipe.rule.steps.push(fields[1..].to_vec()),
+            ("order", Some(recipe)) => recipe.rule.order = fields[1..].to_vec(),
+            ("attr", Some(recipe)) => {
+                recipe.set_attribute(fields.get(1)?, fields.get(2)?.clone()).ok()?
+            }
+            ("tvar", Some(recipe)) => recipe.vars.push(Assignment {
+                name: fields.get(2)?.clone(),
+                flavor: fields.get(3)?.parse().ok()?,
+                value: fields.get(4)?.clone(),
+                propagate: fields[1] == "propagate",
+            }),
+            ("cmd", Some(recipe)) => recipe.add_command(fields.get(2)?.clone(), fields[1] == "+"),
+            ("end", _) => finished = true,
+            _ => return None,
+        }
+    }
+
+    // a daemon that died partway through tells us nothing
+    match finished {
+        true => Some(hayfile),
+        false => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            (c, false) => out.push(c),
+        }
+    }
+    out
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[test]
+fn test_encoding() {
+    use crate::derive::Flavor;
+
+    let mut hayfile = Hayfile {
+        recipes: vec![],
+        vars: Default::default(),
+        pools: Default::default(),
+        files: vec![PathBuf::from("hayfile")],
+    };
+    let tabs = Var::new(String::from("a\tb \\t c"), Flavor::Immediate);
+    hayfile.vars.insert(String::from("tabs"), tabs);
+    let mut release = Var::new(String::from("cc @src\n\tstrip @src"), Flavor::Define);
+    release.params = vec![String::from("src")];
+    hayfile.vars.insert(String::from("release"), release.clone());
+    hayfile.pools.insert(String::from("link"), 2);
+
+    let rule = Rule {
+        outputs: vec![String::from("out.o")],
+        steps: vec![vec![String::from("a.c")], vec![String::from("b.h")]],
+        order: vec![String::from("build")],
+    };
+    let mut recipe = Recipe::from(rule);
+    recipe.set_attribute("depfile", String::from("@out.d")).unwrap();
+    recipe.set_attribute("retry", String::from("2 100ms")).unwrap();
+    recipe.add_command(String::from("cc -c @1 -o @out"), true);
+    recipe.vars.push(Assignment {
+        name: String::from("cflags"),
+        flavor: Flavor::Append,
+        value: String::from("-g"),
+        propagate: true,
+    });
+    hayfile.recipes.push(recipe);
+
+    let text = encode(&hayfile);
+    let decoded = decode(&text).unwrap();
+    assert_eq!(encode(&decoded), text);
+    assert_eq!(decoded.vars["tabs"].value, "a\tb \\t c");
+    assert_eq!(decoded.vars["tabs"].flavor, Flavor::Immediate);
+    assert_eq!(decoded.vars["release"], release);
+    assert_eq!(decoded.recipes[0].rule.steps.len(), 2);
+    assert!(decoded.recipes[0].commands[0].debug);
+    assert_eq!(decoded.recipes[0].vars, hayfile.recipes[0].vars);
+    assert_eq!(decoded.pools["link"], 2);
+
+    assert!(decode(&text.replace("end\n", "")).is_none());
+}
+
+#[test]
+fn test_answer() {
+    let mut cache = HashMap::new();
+
+    // a hayfile that can't be loaded is answered with its errors, rather than ending the daemon
+    let text = answer(&mut cache, Path::new("tests/unreadable.hay"));
+    match reply(&text) {
+        Some(Err(errors)) => assert!(errors.contains("could not read")),
+        _ => panic!("expected the errors loading the hayfile"),
+    }
+    assert!(cache.is_empty());
+
+    let text = answer(&mut cache, Path::new("tests/include.hay"));
+    match reply(&text) {
+        Some(Ok((hayfile, warnings))) => assert!(hayfile.recipes.len() == 5 && warnings.is_empty()),
+        _ => panic!("expected the loaded hayfile"),
+    }
+
+    // warnings come with the hayfile, both when it's loaded and when it's already cached
+    for _ in 0..2 {
+        let text = answer(&mut cache, Path::new("tests/warning.hay"));
+        match reply(&text) {
+            Some(Ok((hayfile, warnings))) => {
+                assert_eq!(hayfile.recipes.len(), 1);
+                assert!(warnings.contains("does not exist"));
+            }
+            _ => panic!("expected the loaded hayfile and its warnings"),
+        }
+    }
+    assert!(cache.contains_key(Path::new("tests/warning.hay")));
+}
@@ -0,0 +1,84 @@
+//
+// Haymaker
+//
+
+/// Parses the make-syntax dependency files written by `gcc -MD` and friends,
+/// returning every prerequisite mentioned in the file
+pub fn parse_depfile(text: &str) -> Vec<String> {
+    let text = text.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut deps: Vec<String> = vec![];
+
+    for line in text.lines() {
+        let rest = match find_colon(line) {
+            Some(colon) => &line[colon + 1..],
+            None => continue,
+        };
+
+        for dep in split_escaped(rest) {
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
+    }
+    deps
+}
+
+fn find_colon(line: &str) -> Option<usize> {
+    // windows drive letters look like C:\ and shouldn't end the target list
+    let bytes = line.as_bytes();
+    for (offset, c) in line.char_indices() {
+        if c != ':' {
+            continue;
+        }
+        match bytes.get(offset + 1) {
+            Some(b'\\') | Some(b'/') if offset == 1 => continue,
+            _ => return Some(offset),
+        }
+    }
+    None
+}
+
+fn split_escaped(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&' ') || chars.peek() == Some(&'#') => {
+                word.push(chars.next().unwrap());
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                word.push(chars.next().unwrap());
+            }
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[test]
+fn test_depfiles() {
+    #[rustfmt::skip]
+    let cases = [
+        ("main.o: main.c main.h", vec!["main.c", "main.h"]),
+        ("main.o: main.c \\\n  include/a.h \\\n  include/b.h\n", vec!["main.c", "include/a.h", "include/b.h"]),
+        ("main.o: main.c a.h\n\na.h:\n", vec!["main.c", "a.h"]),
+        ("a.o b.o: x.h x.h y.h", vec!["x.h", "y.h"]),
+        ("out.o: my\\ file.c cost$$.h", vec!["my file.c", "cost$.h"]),
+        ("C:\\src\\a.o: C:\\src\\a.c", vec!["C:\\src\\a.c"]),
+        ("", vec![]),
+    ];
+
+    for (case, correct) in cases {
+        assert_eq!(parse_depfile(case), correct);
+    }
+}
@@ -2,164 +2,386 @@
 // Haymaker
 //
 
+use crate::ast::{self, Error, ShellNode, Span};
+use crate::condition::{self, Conditionals};
 use crate::console::Color;
 use crate::regexes;
+use crate::shell::ShellParser;
 use crate::text::Text;
 
 use itertools::Itertools;
+use lalrpop_util::ParseError;
 use regex::Regex;
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::process::{Command, Stdio};
 
-pub type VarMap = HashMap<String, String>;
+pub type VarMap = HashMap<String, Var>;
 
-pub fn derive(text: &str, vars: &mut VarMap, debug: bool) -> Result<String, String>  {
-    //
+/// A variable's value, and how it was assigned
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Var {
+    pub value: String,
+    pub flavor: Flavor,
+    pub params: Vec<String>, // the names a macro's arguments are bound to when called
+}
 
-    let mut text = text.to_owned();
-    let mut intos = vec![];
-    let mut steps = match debug {
-        true => vec![text.clone()],
-        false => vec![],
-    };
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavor {
+    Recursive,   // =, derived each time it's used
+    Append,      // +=, onto a variable derived each time it's used
+    Conditional, // ?=, only assigned when undefined
+    Immediate,   // :=, derived once when assigned
+    Override,    // given on the command line, which assignments can't change
+    Environment, // taken from the environment by ?=
+    Define,      // define ... end, several lines derived each time they're used
+}
 
-    let mut count = 0;
+impl Var {
+    pub fn new(value: String, flavor: Flavor) -> Self {
+        Var {
+            value,
+            flavor,
+            params: vec![],
+        }
+    }
 
-    'top: loop {
-        if count == 1024 {
-            break;
+    /// Whether the value is used as is, rather than derived on use
+    pub fn literal(&self) -> bool {
+        matches!(
+            self.flavor,
+            Flavor::Immediate | Flavor::Override | Flavor::Environment
+        )
+    }
+}
+
+impl From<String> for Var {
+    fn from(value: String) -> Self {
+        Var::new(value, Flavor::Recursive)
+    }
+}
+
+impl std::fmt::Display for Flavor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Flavor::Recursive => "recursive",
+            Flavor::Append => "append",
+            Flavor::Conditional => "conditional",
+            Flavor::Immediate => "immediate",
+            Flavor::Override => "override",
+            Flavor::Environment => "environment",
+            Flavor::Define => "define",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for Flavor {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "recursive" => Ok(Flavor::Recursive),
+            "append" => Ok(Flavor::Append),
+            "conditional" => Ok(Flavor::Conditional),
+            "immediate" => Ok(Flavor::Immediate),
+            "override" => Ok(Flavor::Override),
+            "environment" => Ok(Flavor::Environment),
+            "define" => Ok(Flavor::Define),
+            _ => Err(format!("unknown flavor {}", text)),
         }
-        count += 1;
+    }
+}
 
-        let iter = text
-            .char_indices()
-            .chain(std::iter::once((text.len(), ' ')))
-            .tuple_windows();
+/// An assignment kept to be made later, such as one of a target's own variables
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assignment {
+    pub name: String,
+    pub flavor: Flavor,
+    pub value: String,
+    pub propagate: bool, // whether a target's prerequisites see it too
+}
 
-        let mut start = None;
-        let mut end = None;
-        let mut quoted = false;
+impl Flavor {
+    /// The flavor assigned by an operator such as +=
+    pub fn from_operator(operator: &str) -> Option<Self> {
+        match operator {
+            "=" => Some(Flavor::Recursive),
+            "+=" => Some(Flavor::Append),
+            "?=" => Some(Flavor::Conditional),
+            ":=" => Some(Flavor::Immediate),
+            _ => None,
+        }
+    }
 
-        for ((offset, c), (_, n)) in iter {
-            if c == '\'' {
-                quoted = !quoted;
-            }
-            if quoted {
-                continue;
-            }
+    pub fn operator(&self) -> &'static str {
+        match self {
+            Flavor::Append => "+=",
+            Flavor::Conditional => "?=",
+            Flavor::Immediate => ":=",
+            _ => "=",
+        }
+    }
+}
 
-            if let ('@', '(') = (c, n) {
-                start = Some(offset);
-            }
+/// Assigns a value to variables the way its operator asks, leaving overrides alone
+pub fn assign(
+    vars: &mut VarMap,
+    names: &[String],
+    flavor: Flavor,
+    value: &str,
+    debug: bool,
+) -> Result<(), Error> {
+    // appending to a variable that's used as is means deriving what's appended too
+    let literal = names.iter().any(|name| vars.get(name).is_some_and(Var::literal));
+    let derived = match flavor {
+        Flavor::Immediate => derive(value, vars, debug)?,
+        Flavor::Append if literal => derive(value, vars, debug)?,
+        _ => String::new(),
+    };
 
-            if start.is_some() && c == ')' {
-                end = Some(offset + 1);
-                break;
+    for name in names {
+        let var = match (flavor, vars.get(name)) {
+            (_, Some(var)) if var.flavor == Flavor::Override => continue,
+            (Flavor::Conditional, Some(_)) => continue,
+            (Flavor::Conditional, None) => match std::env::var(name) {
+                Ok(value) => Var::new(value, Flavor::Environment),
+                Err(_) => Var::new(value.to_string(), Flavor::Conditional),
+            },
+            (Flavor::Immediate, _) => Var::new(derived.clone(), Flavor::Immediate),
+            (Flavor::Append, Some(var)) if var.literal() => {
+                let value = [var.value.as_str(), &derived].join(" ");
+                Var::new(value.trim().to_string(), var.flavor)
+            }
+            (Flavor::Append, Some(var)) => {
+                let value = [var.value.as_str(), value].join(" ");
+                Var::new(value.trim().to_string(), Flavor::Append)
             }
+            (Flavor::Append, None) => Var::new(value.to_string(), Flavor::Append),
+            (flavor, _) => Var::new(value.to_string(), flavor),
+        };
+        vars.insert(name.clone(), var);
+    }
+    Ok(())
+}
 
-            if c == '@' {
-                if let Some(mat) = regexes::VAR_AT_WITH_SIGN.find(&text[offset..]) {
-                    let start = offset + mat.start();
-                    let end = offset + mat.end();
-                    let var = &mat.as_str()[1..];
+/// How deeply derivations may nest, such as variables holding variables, before giving up
+const MAX_DEPTH: usize = 256;
 
-                    let replace = match vars.get(var) {
-                        Some(replace) => replace,
-                        None => "",
-                    };
+thread_local! {
+    // how many macro calls are being derived, since each call starts a new derivation
+    static CALLS: Cell<usize> = const { Cell::new(0) };
+}
 
-                    if debug {
-                        intos.push((format!("{} » {}", var, replace.or_quotes()), None));
-                    }
+/// Parses a line and evaluates its variables and subcalls
+pub fn derive(text: &str, vars: &mut VarMap, debug: bool) -> Result<String, Error> {
+    let mut derivation = Derivation {
+        vars,
+        debug,
+        steps: vec![],
+        expanding: vec![],
+        depth: 0,
+    };
+    let result = derivation.text(text);
 
-                    text = match end < text.len() {
-                        true => text[0..start].to_owned() + &replace + &text[end..],
-                        false => text[0..start].to_owned() + &replace,
-                    };
-                    if debug {
-                        steps.push(text.clone());
-                    }
-                    continue 'top;
-                }
+    if debug {
+        println!("{} {}", "derive".pink(), add_derivation_highlights(text));
+
+        for (into, subcall) in &derivation.steps {
+            if let Some(subcall) = subcall {
+                let pipe = "║".blue();
+                let margin = format!("\n  {} ", pipe);
+                println!("\n  {} {}\n", pipe, subcall.replace("\n", &margin));
             }
+            println!("  {}", into.dim());
+        }
+        if let Ok(result) = &result {
+            println!("  {} {}", "»".dim(), add_derivation_highlights(result));
         }
+        println!();
+    }
+    result
+}
 
-        if let (Some(start), Some(end)) = (start, end) {
-            let inner = text[start + 2..end - 1].to_owned();
+/// Evaluates ShellNode trees, keeping track of what's being expanded
+struct Derivation<'a> {
+    vars: &'a mut VarMap,
+    debug: bool,
+    steps: Vec<(String, Option<String>)>, // what each node derived into, with any subcall's trace
+    expanding: Vec<String>,               // the variables being expanded, to catch cycles
+    depth: usize,
+}
 
-            if let Some(mat) = regexes::VAR_AT.find(&inner) {
-                let mat = mat.as_str();
-                let var = match vars.get(mat) {
-                    Some(replace) => replace,
-                    None => "",
-                };
+impl Derivation<'_> {
+    fn text(&mut self, text: &str) -> Result<String, Error> {
+        if self.depth == MAX_DEPTH {
+            let message = String::from("derivation nests too deeply");
+            return Err(Error {
+                message,
+                span: (0, text.len()),
+            });
+        }
 
-                let end = start + 2 + mat.len();
+        let tokens = ast::lex(text)?;
+        let tree = match ShellParser::new().parse(tokens.into_iter().map(Ok)) {
+            Ok(tree) => tree,
+            Err(ParseError::User { error }) => return Err(error),
+            Err(_) => {
+                // the lexer balances subcalls, so the grammar accepts every line it lexes
+                return Err(Error {
+                    message: String::from("could not parse this line"),
+                    span: (0, text.len()),
+                });
+            }
+        };
 
-                let var = format!(" {}", var);
-                text = text[0..start + 2].to_owned() + &var + &text[end..];
+        self.depth += 1;
+        let result = self.node(&tree);
+        self.depth -= 1;
+        result
+    }
 
-                if debug {
-                    steps.push(text.clone());
-                    intos.push((format!("{} » '{}'", mat, var.or_quotes()), None));
+    /// Derives text from elsewhere, such as a variable, blaming any error on the node using it
+    fn nested(&mut self, text: &str, span: Span) -> Result<String, Error> {
+        self.text(text).map_err(|err| Error { span, ..err })
+    }
+
+    fn nodes(&mut self, nodes: &[ShellNode]) -> Result<String, Error> {
+        let mut text = String::new();
+        for node in nodes {
+            text += &self.node(node)?;
+        }
+        Ok(text)
+    }
+
+    fn node(&mut self, node: &ShellNode) -> Result<String, Error> {
+        match node {
+            ShellNode::Root(nodes) => self.nodes(nodes),
+            ShellNode::Text(text) => Ok(text.clone()),
+            ShellNode::Expand(var, span) => {
+                if self.expanding.contains(var) {
+                    return Err(Error {
+                        message: format!("{} refers to itself", var.red()),
+                        span: *span,
+                    });
                 }
-                continue;
-            }
 
-            let (replace, printable, status) = subcall(&inner, vars, debug);
-            if let Err(message) = status {
-                return Err(message);
+                // most variables are kept raw, so derive them as they're used
+                let value = match self.vars.get(var).cloned() {
+                    Some(value) if value.literal() => value.value,
+                    Some(value) => {
+                        self.expanding.push(var.clone());
+                        let value = self.nested(&value.value, *span);
+                        self.expanding.pop();
+                        value?
+                    }
+                    None => String::new(),
+                };
+
+                if self.debug {
+                    self.steps.push((format!("{} » {}", var, value.or_quotes()), None));
+                }
+                Ok(value)
             }
+            ShellNode::Subcall {
+                parts,
+                rederive,
+                span,
+            } => {
+                let mut texts = vec![];
+                for part in parts {
+                    texts.push(self.nodes(part)?);
+                }
 
-            text = match end < text.len() {
-                true => text[0..start].to_owned() + &replace + &text[end..],
-                false => text[0..start].to_owned() + &replace,
-            };
-            if debug {
-                steps.push(text.clone());
-                intos.push((format!("@(..) » {}", replace.or_quotes()), Some(printable)));
+                let (result, printable, status) = subcall(&texts, self.vars, self.debug);
+                if self.debug {
+                    let into = format!("@(..) » {}", result.or_quotes());
+                    self.steps.push((into, Some(printable)));
+                }
+
+                if let Err(message) = status {
+                    return Err(Error {
+                        message,
+                        span: *span,
+                    });
+                }
+
+                match rederive {
+                    true => self.nested(&result, *span),
+                    false => Ok(result),
+                }
             }
-            continue;
         }
-
-        break;
     }
+}
 
-    if debug {
-        let mut steps = steps.into_iter();
-        let first = steps.next().unwrap();
-        println!("{} {}", "derive".pink(), add_derivation_highlights(&first));
+/// Keeps the lines of a macro in the branches of its ifs that hold once its parameters are bound,
+/// reading the ifs the way those in the hayfile are
+fn branches(body: &str, scope: &mut VarMap, debug: bool) -> Result<String, String> {
+    let mut conditionals = Conditionals::default();
+    let mut kept = vec![];
 
-        let width = intos.iter().map(|x| x.0.len()).max().unwrap_or(0);
+    for line in body.split('\n') {
+        let shell = line.starts_with(char::is_whitespace);
+        let condition = regexes::CONDITION.captures(line).filter(|_| !shell);
 
-        for (step, (into, subcall)) in steps.zip(intos.iter()) {
-            if let Some(subcall) = subcall {
-                let pipe = "║".blue();
-                let margin = format!("\n  {} ", pipe);
-                println!("\n  {} {}\n", pipe, subcall.replace("\n", &margin));
+        let branch = match condition {
+            Some(caps) => {
+                let chained = caps.get(1).is_some();
+                let decides = match chained {
+                    true => conditionals.undecided(),
+                    false => conditionals.active(),
+                };
+                let holds = match decides {
+                    true => {
+                        condition::evaluate(&caps[2], scope, debug).map_err(|err| err.message)?
+                    }
+                    false => false,
+                };
+                match chained {
+                    true => conditionals.otherwise(Some(holds)),
+                    false => {
+                        conditionals.open(holds, ("", 0, 0));
+                        Ok(())
+                    }
+                }
             }
-            let spacing = " ".repeat(width - into.len());
-            println!("  {} {} {}", into.dim(), spacing, add_derivation_highlights(&step));
-        }
-        println!();
+            None if !shell && line.trim_end() == "else" => conditionals.otherwise(None),
+            None if !shell && line.trim_end() == "end" => conditionals.end(),
+            None => {
+                if conditionals.active() {
+                    kept.push(line);
+                }
+                continue;
+            }
+        };
+        branch?;
     }
 
-    if count == 1024 {
-        panic!("Too many times");
+    match conditionals.unclosed() {
+        Some(_) => Err(String::from("an if is never ended")),
+        None => Ok(kept.join("\n")),
     }
-
-    Ok(text.to_string())
 }
 
-fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Result<(), String>) {
+fn subcall(
+    parts: &[String],
+    vars: &mut VarMap,
+    debug: bool,
+) -> (String, String, Result<(), String>) {
     let mut printable = String::new();
-    let mut defs = VarMap::new();
+    let mut defs = HashMap::new();
 
     let part_regex = Regex::new(r"(\S+)\s*(\S.*)?").unwrap();
     let args_regex = Regex::new(r#"'[^']*'|"[^"]*"|\S+"#).unwrap();
 
-    let mut parts = text.split_when_balanced('|', '\'').into_iter();
+    let full = parts
+        .iter()
+        .map(|part| part.trim())
+        .join(&format!(" {} ", "|".blue()));
+    let parts: Vec<_> = parts.iter().filter(|part| !part.is_empty()).collect();
+    let mut parts = parts.into_iter();
 
     let mut state = match parts.next() {
         Some(state) => state.trim().to_owned(),
@@ -180,7 +402,6 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
     }
 
     if debug {
-        let full = text.trim().replace("|", &"|".blue());
         save!("{} {}{}{}", "subcall".blue(), "@(".blue(), full, ")".blue());
 
         if parts.as_slice().is_empty() {
@@ -216,8 +437,7 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
         for (index, input) in args_regex.find_iter(&state).enumerate() {
             let mut input = input.as_str();
 
-            let has_quotes =
-                input.chars().next() == Some('\'') && input.chars().rev().next() == Some('\'');
+            let has_quotes = input.starts_with('\'') && input.ends_with('\'');
 
             if has_quotes && input.len() >= 2 {
                 input = &input[1..(input.len() - 1)];
@@ -255,8 +475,7 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
                 for (index, arg) in args_regex.find_iter(args_str).enumerate() {
                     let mut arg = arg.as_str();
 
-                    let has_quotes =
-                        arg.chars().next() == Some('\'') && arg.chars().rev().next() == Some('\'');
+                    let has_quotes = arg.starts_with('\'') && arg.ends_with('\'');
 
                     if has_quotes && arg.len() >= 2 {
                         arg = &arg[1..(arg.len() - 1)];
@@ -309,10 +528,10 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
                 state = inputs.iter().map(|s| s.replace(|_| true, "-")).join(" ");
             }
             "include" => {
-                state = inputs.iter().filter(|s| args.contains(&s)).join(" ");
+                state = inputs.iter().filter(|s| args.contains(s)).join(" ");
             }
             "exclude" => {
-                state = inputs.iter().filter(|s| !args.contains(&s)).join(" ");
+                state = inputs.iter().filter(|s| !args.contains(s)).join(" ");
             }
             "quote" => {
                 state = inputs.iter().map(|s| format!("'{}'", s)).join(" ");
@@ -325,7 +544,7 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
                 inputs.sort();
                 state = inputs.iter().join(" ");
             }
-            "first" => match inputs.get(0) {
+            "first" => match inputs.first() {
                 Some(first) => state = first.to_string(),
                 None => error!("no first input"),
             },
@@ -336,18 +555,57 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
             "def" => {
                 for arg in &args {
                     defs.insert(arg.to_string(), state.to_owned());
-                    vars.insert(arg.to_string(), state.to_owned());
+                    vars.insert(arg.to_string(), Var::from(state.to_owned()));
+                }
+            }
+            "call" => {
+                let (name, values) = match args.split_first() {
+                    Some((name, values)) => (*name, values.iter().chain(&inputs)),
+                    None => error!("call: needs the name of a macro"),
+                };
+                let called = match vars.get(name) {
+                    Some(_) if CALLS.get() == MAX_DEPTH => {
+                        error!("call: {} nests too deeply", name)
+                    }
+                    Some(called) => called.clone(),
+                    None => error!("call: no macro named {}", name),
+                };
+
+                // the arguments, then the inputs, fill the parameters, which hold them as is
+                let values = values.map(|value| value.to_string()).collect_vec();
+                if values.len() > called.params.len() {
+                    let count = called.params.len();
+                    error!("call: {} takes {} {}", name, count, "argument".plural(count));
+                }
+                let mut scope = vars.clone();
+                for (index, param) in called.params.iter().enumerate() {
+                    let value = values.get(index).cloned().unwrap_or_default();
+                    scope.insert(param.clone(), Var::new(value, Flavor::Immediate));
+                }
+
+                let body = match branches(&called.value, &mut scope, debug) {
+                    Ok(body) => body,
+                    Err(message) => error!("call: {}: {}", name, message),
+                };
+
+                CALLS.set(CALLS.get() + 1);
+                let result = derive(&body, &mut scope, debug);
+                CALLS.set(CALLS.get() - 1);
+
+                match result {
+                    Ok(result) => state = result,
+                    Err(err) => error!("call: {}: {}", name, err.message),
                 }
             }
             "drop" => {
-                let count = match args.get(0) {
+                let count = match args.first() {
                     Some(arg) => number!(arg, "drop"),
                     None => 1,
                 };
                 state = inputs.iter().skip(count).join(" ");
             }
             "pop" => {
-                let count = match args.get(0) {
+                let count = match args.first() {
                     Some(arg) => number!(arg, "pop"),
                     None => 1,
                 };
@@ -372,7 +630,7 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
                 state = outputs.join(" ");
             }
             "between" => {
-                let first = match args.get(0) {
+                let first = match args.first() {
                     Some(arg) => number!(arg, "between").saturating_sub(1),
                     None => 0,
                 };
@@ -402,7 +660,7 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
                 for mut arg in args {
                     let mut flip = false;
 
-                    if arg.chars().next() == Some('-') {
+                    if arg.starts_with('-') {
                         arg = &arg[1..];
                         flip = true;
                     }
@@ -515,20 +773,14 @@ fn subcall(text: &str, vars: &mut VarMap, debug: bool) -> (String, String, Resul
             "split" => {
                 let mut outputs: Vec<_> = inputs;
                 for arg in &args {
-                    outputs = outputs
-                        .into_iter()
-                        .map(|s| s.split(arg))
-                        .flatten()
-                        .map(|s| s)
-                        .collect();
+                    outputs = outputs.into_iter().flat_map(|s| s.split(arg)).collect();
                 }
                 state = outputs.into_iter().filter(|s| s != &"").join(" ");
             }
             "unquote" => {
                 let mut outputs = vec![];
                 for mut input in inputs {
-                    let has_quotes = input.chars().next() == Some('\"')
-                        && input.chars().rev().next() == Some('\"');
+                    let has_quotes = input.starts_with('\"') && input.ends_with('\"');
 
                     if has_quotes && input.len() >= 2 {
                         input = &input[1..(input.len() - 1)];
@@ -700,33 +952,103 @@ fn test_subcalls() {
     let mut vars = VarMap::new();
 
     for (case, correct) in cases {
-        let (text, printable) = subcall(&case, &mut vars, true);
+        let parts = case.split_when_balanced_with_offsets('|', '\'');
+        let parts = parts.into_iter().map(|(_, part)| part.to_string()).collect_vec();
+        let (text, printable, _) = subcall(&parts, &mut vars, true);
         println!("{}", printable);
         assert_eq!(&text, &correct);
     }
 
-    assert_eq!(vars.get("key1"), Some(&String::from("a definition")));
-    assert_eq!(vars.get("key2"), Some(&String::from("a definition")));
+    assert_eq!(vars["key1"].value, "a definition");
+    assert_eq!(vars["key2"].value, "a definition");
 }
 
 #[test]
 fn test_derivation() {
-    let mut vars = HashMap::new();
-    vars.insert(String::from("out"), String::from("bin"));
-    vars.insert(String::from("1"), String::from("aa"));
-    vars.insert(String::from("2"), String::from("bb"));
+    let mut vars = VarMap::new();
+    let mut set = |name: &str, value: &str, flavor| {
+        vars.insert(name.to_string(), Var::new(value.to_string(), flavor));
+    };
+    set("out", "bin", Flavor::Recursive);
+    set("1", "aa", Flavor::Recursive);
+    set("2", "bb", Flavor::Recursive);
+    set("nested", "@1 @(out)", Flavor::Recursive);
+    set("loop", "x @(loop)", Flavor::Recursive);
+    set("literal", "@1 @(out)", Flavor::Immediate);
 
     #[rustfmt::skip]
     let cases = [
         ("echo hi", "echo hi"),
-        ("@1 @out @1 '@out' @( '@' | noop)2", "aa bin aa '@out' bb"),
         ("@1 '@2' @('@' | noop)", "aa '@2' @"),
-        ("@(out) @out @(@(out)) @(@(out | noop)) out", "bin bin   out"),
         ("@( out) @( out | noop) @(1)", "out out aa"),
+        ("a | (b) @( x y (z) | filter ^(x|z)$)", "a | (b) x"),
+        ("@nested @(nested | add c)", "aa bin aa bin c"),
+        ("@literal", "@1 @(out)"),
     ];
 
     for (case, correct) in cases {
-        let line = derive(&case, &mut vars, true).unwrap();
+        let line = derive(case, &mut vars, true).unwrap();
         assert_eq!(&line, &correct);
     }
+
+    let error = derive("ok @(a | add @loop)", &mut vars, false).unwrap_err();
+    assert_eq!(error.span, (13, 18));
+    assert_eq!(derive("@(a | drop x)", &mut vars, false).unwrap_err().span, (0, 13));
+
+    let mut release = Var::new(String::from("cc @src -o @dest\nstrip @dest"), Flavor::Define);
+    release.params = vec![String::from("src"), String::from("dest")];
+    vars.insert(String::from("release"), release);
+    let called = derive("@(@1 | call release a.c)", &mut vars, false).unwrap();
+    assert_eq!(called, "cc a.c -o aa\nstrip aa");
+    assert!(derive("@(@1 @2 | call release a.c)", &mut vars, false).is_err());
+}
+
+#[test]
+fn test_rederive() {
+    let mut vars = VarMap::new();
+    vars.insert(String::from("out"), Var::new(String::from("bin"), Flavor::Recursive));
+    vars.insert(String::from("1"), Var::new(String::from("aa"), Flavor::Recursive));
+    vars.insert(String::from("2"), Var::new(String::from("bb"), Flavor::Recursive));
+
+    // a subcall's result is no longer scanned for more derivations, so an @ it makes stays,
+    // while @@( opts back in, deriving the result again as every subcall once did
+    #[rustfmt::skip]
+    let cases = [
+        ("@1 @out @1 '@out' @( '@' | noop)2", "aa bin aa '@out' @2"),
+        ("@( '@2' | noop) @@( '@2' | noop)", "@2 bb"),
+        ("@(out) @out @(@(out)) @(@(out | noop)) out", "bin bin bin bin out"),
+        ("@( '@1' | noop) @@( '@1' | noop) @@( '@@(2' ')' | concat)", "@1 aa bb"),
+    ];
+
+    for (case, correct) in cases {
+        let line = derive(case, &mut vars, false).unwrap();
+        assert_eq!(&line, &correct);
+    }
+}
+
+#[test]
+fn test_assign() {
+    let mut vars = VarMap::new();
+    let names = |names: &str| names.split(' ').map(String::from).collect_vec();
+    let mut set = |name: &str, flavor, value: &str| {
+        assign(&mut vars, &names(name), flavor, value, false).unwrap();
+    };
+
+    set("base", Flavor::Recursive, "one");
+    set("cflags", Flavor::Recursive, "-O2");
+    set("cflags", Flavor::Append, "@base");
+    set("now", Flavor::Immediate, "@base");
+    set("now", Flavor::Append, "@base");
+    set("a b", Flavor::Conditional, "x");
+    set("b", Flavor::Conditional, "y");
+    set("base", Flavor::Recursive, "two");
+
+    assert_eq!(vars["cflags"], Var::new(String::from("-O2 @base"), Flavor::Append));
+    assert_eq!(vars["now"], Var::new(String::from("one one"), Flavor::Immediate));
+    assert_eq!(vars["b"], Var::new(String::from("x"), Flavor::Conditional));
+    assert_eq!(derive("@cflags @now", &mut vars, false).unwrap(), "-O2 two one one");
+
+    vars.insert(String::from("cc"), Var::new(String::from("clang"), Flavor::Override));
+    assign(&mut vars, &names("cc"), Flavor::Recursive, "gcc", false).unwrap();
+    assert_eq!(vars["cc"].value, "clang");
 }
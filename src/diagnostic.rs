@@ -0,0 +1,132 @@
+//
+// Haymaker
+//
+
+use crate::console::{self, Color};
+use crate::text::Text;
+
+/// A problem found while loading a hayfile
+pub struct Diagnostic {
+    filename: String,
+    lineno: usize,
+    column: usize,
+    text: String,
+    neglected: bool, // whether the line was marked with ^, so the build goes on anyway
+}
+
+impl Diagnostic {
+    pub fn neglect(&mut self, neglected: bool) {
+        self.neglected = neglected;
+    }
+}
+
+/// Collects the problems in a hayfile, so that they can all be fixed from a single run
+#[derive(Default)]
+pub struct Diagnostics {
+    found: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    /// Records an error about a whole file
+    pub fn file_error(&mut self, kind: &str, message: &str, filename: &str) -> &mut Diagnostic {
+        let text = console::file_error(kind, message, filename);
+        self.add(filename, 0, 0, text)
+    }
+
+    /// Records an error pointing at a column of a line in the hayfile
+    pub fn source_error(
+        &mut self,
+        kind: &str,
+        message: &str,
+        filename: &str,
+        line: &str,
+        num: usize,
+        column: usize,
+    ) -> &mut Diagnostic {
+        let text = console::source_error(kind, message, filename, line, num, column);
+        self.add(filename, num, column, text)
+    }
+
+    /// Records an error in a line as it was derived
+    #[allow(clippy::too_many_arguments)]
+    pub fn processed_error(
+        &mut self,
+        kind: &str,
+        message: &str,
+        filename: &str,
+        line: &str,
+        info: Vec<String>,
+        num: usize,
+        column: usize,
+    ) -> &mut Diagnostic {
+        let text = console::processed_error(kind, message, filename, line, info, num, column);
+        self.add(filename, num, column, text)
+    }
+
+    fn add(
+        &mut self,
+        filename: &str,
+        lineno: usize,
+        column: usize,
+        text: String,
+    ) -> &mut Diagnostic {
+        self.found.push(Diagnostic {
+            filename: filename.to_string(),
+            lineno,
+            column,
+            text,
+            neglected: false,
+        });
+        self.found.last_mut().unwrap()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.found.is_empty()
+    }
+
+    /// How many problems stop the build
+    pub fn errors(&self) -> usize {
+        self.found.iter().filter(|found| !found.neglected).count()
+    }
+
+    /// The text of each problem, in the order they were found
+    #[cfg(test)]
+    pub fn texts(&self) -> Vec<&str> {
+        self.found.iter().map(|found| found.text.as_str()).collect()
+    }
+
+    /// Renders every problem in the order they appear in each file, then how many stop the build
+    pub fn render(&mut self) -> String {
+        self.found.sort_by(|a, b| {
+            (&a.filename, a.lineno, a.column).cmp(&(&b.filename, b.lineno, b.column))
+        });
+
+        let mut text: String = self.found.iter().map(|found| found.text.as_str()).collect();
+
+        let errors = self.errors();
+        if errors > 0 {
+            text += &format!("{} {}\n", errors, "error".plural(errors).red());
+        }
+        text
+    }
+
+    pub fn print(&mut self) {
+        print!("{}", self.render());
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let mut diagnostics = Diagnostics::default();
+    diagnostics.source_error("Structure", "late", "hayfile", "b", 9, 0);
+    diagnostics.source_error("Include", "early", "hayfile", "a", 2, 4);
+    diagnostics
+        .source_error("Subcall", "ignored", "hayfile", "c", 2, 1)
+        .neglect(true);
+    assert_eq!(diagnostics.errors(), 2);
+
+    diagnostics.print();
+    let order = diagnostics.found.iter().map(|found| (found.lineno, found.column));
+    assert_eq!(order.collect::<Vec<_>>(), vec![(2, 1), (2, 4), (9, 0)]);
+}
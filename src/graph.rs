@@ -0,0 +1,537 @@
+//
+// Haymaker
+//
+
+use crate::console::Color;
+use crate::derive::VarMap;
+use crate::progress::{Blocks, Log, Progress};
+use crate::recipe::{Buffer, Dependency, Failure, Recipe, Settings};
+use crate::signals;
+use crate::state::State;
+use crate::text::Text;
+
+use itertools::Itertools;
+use petgraph::stable_graph::{NodeIndex, StableGraph};
+use petgraph::visit::{Dfs, EdgeFiltered, EdgeRef};
+use petgraph::Direction;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Every recipe, with edges pointing from each recipe to the recipes building its inputs
+pub struct Graph {
+    graph: StableGraph<Recipe, Dependency>,
+    nodes: BTreeMap<String, NodeIndex>, // output -> the recipe building it
+    pools: BTreeMap<String, usize>,     // pool -> how many of its recipes may run at once
+}
+
+/// What happened during a run of the graph
+#[derive(Default)]
+pub struct Report {
+    pub cases: Vec<Case>,
+    pub undeclared: usize,
+    pub retried: Vec<(String, u32)>, // targets that needed more than one attempt
+    pub wall: Duration,
+    pub cpu: Duration, // spent by haymaker and the commands it ran
+}
+
+/// What happened to one of the targets
+pub struct Case {
+    pub target: String,
+    pub time: Duration,
+    pub verdict: Verdict,
+}
+
+pub enum Verdict {
+    Built,
+    Fresh, // already up to date
+    Cached,
+    Failed(Failure), // including interrupted recipes
+    Skipped,         // never started because something failed
+}
+
+/// How many of the slowest recipes the report lists
+const SLOWEST: usize = 5;
+
+impl Graph {
+    pub fn new(
+        recipes: Vec<Recipe>,
+        pools: BTreeMap<String, usize>,
+        state: &State,
+    ) -> Result<Self, String> {
+        let mut graph: StableGraph<Recipe, Dependency> = StableGraph::new();
+        let mut nodes = BTreeMap::new();
+
+        for mut recipe in recipes {
+            if let Some(pool) = &recipe.pool {
+                if !pools.contains_key(pool) {
+                    let target = recipe.target();
+                    return Err(format!("No pool named {} for {}", pool.red(), target.blue()));
+                }
+            }
+            if let Some(deps) = state.deps.get(&recipe.target()) {
+                recipe.implicit = deps.clone();
+            }
+
+            let outputs = recipe.rule.outputs.clone();
+            let node = graph.add_node(recipe);
+
+            for output in outputs {
+                nodes.insert(output, node);
+            }
+        }
+
+        for node in graph.node_indices().collect_vec() {
+            let recipe = &graph[node];
+            let rule = &recipe.rule;
+            let normal = rule.steps.iter().flatten().map(|input| (input, Dependency::Normal));
+            let order = rule.order.iter().map(|input| (input, Dependency::Order));
+            let implicit = recipe.implicit.iter().map(|input| (input, Dependency::Normal));
+            let mut edges = vec![];
+
+            for (input, kind) in normal.chain(implicit).chain(order) {
+                match nodes.get(input) {
+                    Some(&dep) => edges.push((dep, kind)),
+                    None if Path::new(input).exists() => {}
+                    None if recipe.implicit.contains(input) => {} // stale depfiles may list deleted headers
+                    None => {
+                        let target = recipe.target();
+                        return Err(format!(
+                            "No recipe to make {} for {}",
+                            input.red(),
+                            target.blue()
+                        ));
+                    }
+                }
+            }
+
+            for (dep, kind) in edges {
+                graph.add_edge(node, dep, kind);
+            }
+        }
+
+        for cycle in petgraph::algo::tarjan_scc(&graph) {
+            let looped = cycle.len() > 1 || graph.contains_edge(cycle[0], cycle[0]);
+            if looped {
+                let cycle = cycle.iter().map(|&node| graph[node].target()).join(", ");
+                return Err(format!("Dependency cycle between {}", cycle.red()));
+            }
+        }
+
+        // propagated variables reach every prerequisite, those of the furthest targets first
+        for node in petgraph::algo::toposort(&graph, None).unwrap_or_default() {
+            let vars = graph[node].vars.iter().filter(|var| var.propagate);
+            let vars = vars.cloned().collect_vec();
+            if vars.is_empty() {
+                continue;
+            }
+
+            // order-only prerequisites only affect when a target is built, not how
+            let normal = EdgeFiltered::from_fn(&graph, |edge| *edge.weight() == Dependency::Normal);
+            let mut dfs = Dfs::new(&normal, node);
+            let mut deps = vec![];
+            while let Some(dep) = dfs.next(&normal) {
+                if dep != node {
+                    deps.push(dep);
+                }
+            }
+            for dep in deps {
+                graph[dep].inherited.extend(vars.iter().cloned());
+            }
+        }
+
+        Ok(Graph {
+            graph,
+            nodes,
+            pools,
+        })
+    }
+
+    /// The recipes needed to build the named goals, or every recipe when none are named
+    pub fn goals(&self, names: &[String]) -> Result<HashSet<NodeIndex>, String> {
+        if names.is_empty() {
+            return Ok(self.graph.node_indices().collect());
+        }
+
+        let mut needed = HashSet::new();
+        let mut stack = vec![];
+
+        for name in names {
+            match self.nodes.get(name) {
+                Some(&node) => stack.push(node),
+                None => return Err(format!("No recipe to make {}", name.red())),
+            }
+        }
+
+        while let Some(node) = stack.pop() {
+            if needed.insert(node) {
+                stack.extend(self.graph.neighbors_directed(node, Direction::Outgoing));
+            }
+        }
+        Ok(needed)
+    }
+
+    /// The files the given recipes read that no recipe builds
+    pub fn sources(&self, targets: &HashSet<NodeIndex>) -> Vec<String> {
+        let mut sources = vec![];
+
+        for &node in targets {
+            let recipe = &self.graph[node];
+            let inputs = recipe.rule.steps.iter().flatten().chain(&recipe.implicit);
+
+            for input in inputs {
+                let external = !self.nodes.contains_key(input) && !Path::new(input).is_absolute();
+                if external && !sources.contains(input) {
+                    sources.push(input.clone());
+                }
+            }
+        }
+        sources
+    }
+
+    /// The recipes reading any of the changed files, and everything built from them
+    pub fn affected(&self, changed: &[String]) -> HashSet<NodeIndex> {
+        let mut stack = vec![];
+
+        for node in self.graph.node_indices() {
+            let recipe = &self.graph[node];
+            let mut inputs = recipe.rule.steps.iter().flatten().chain(&recipe.implicit);
+
+            if inputs.any(|input| changed.contains(input)) {
+                stack.push(node);
+            }
+        }
+
+        let mut affected = HashSet::new();
+        while let Some(node) = stack.pop() {
+            if affected.insert(node) {
+                let dependents = self.graph.edges_directed(node, Direction::Incoming);
+                let dependents = dependents.filter(|edge| *edge.weight() == Dependency::Normal);
+                stack.extend(dependents.map(|edge| edge.source()));
+            }
+        }
+        affected
+    }
+
+    /// Runs the stale recipes among the targets, dependencies first,
+    /// with as many at once as the job limit and their pools allow
+    pub fn run(
+        &self,
+        targets: &HashSet<NodeIndex>,
+        vars: &VarMap,
+        state: &mut State,
+        settings: &Settings,
+    ) -> Report {
+        let mut report = Report::default();
+        let (wall, cpu) = (Instant::now(), cpu_time());
+        let stale = self.stale(targets);
+
+        let estimates = stale.iter().sorted().map(|&node| {
+            let target = self.graph[node].target();
+            let time = state.times.get(&target).cloned();
+            (target, time)
+        });
+        let mut progress = Progress::new(&estimates.collect_vec(), settings.jobs);
+        let mut started = HashMap::new();
+
+        let order = match settings.buffer {
+            Some(Buffer::Stable) => Some(self.order(&stale)),
+            _ => None,
+        };
+        let mut blocks = Blocks::new(order);
+        let buffered = settings.buffer.is_some();
+
+        // how many of each recipe's dependencies have yet to run
+        let mut pending = BTreeMap::new();
+        let mut ready = VecDeque::new();
+
+        for &node in targets.iter().sorted() {
+            let deps = self.graph.neighbors_directed(node, Direction::Outgoing);
+            let count = deps.unique().filter(|dep| targets.contains(dep)).count();
+            match count {
+                0 => ready.push_back(node),
+                _ => drop(pending.insert(node, count)),
+            }
+        }
+
+        let mut running = 0;
+        let mut pools: HashMap<&str, usize> = HashMap::new(); // how many recipes each pool is running
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::scope(|scope| loop {
+            // after a failure, the running recipes may finish but no more start
+            let stopping = report.failed() > 0 || signals::interrupted().is_some();
+            let mut index = 0;
+
+            while !stopping && running < settings.jobs && index < ready.len() {
+                let node = ready[index];
+                let recipe = &self.graph[node];
+
+                if !stale.contains(&node) {
+                    report.push(recipe.target(), Duration::ZERO, Verdict::Fresh);
+                    ready.remove(index);
+                    self.finish(node, &mut pending, &mut ready);
+                    continue;
+                }
+
+                if let Some(pool) = &recipe.pool {
+                    let used = pools.entry(pool.as_str()).or_default();
+                    if *used == self.pools[pool] {
+                        index += 1; // try the next recipe instead
+                        continue;
+                    }
+                    *used += 1;
+                }
+
+                ready.remove(index);
+                running += 1;
+
+                let target = recipe.target();
+                progress.start(target.clone(), state.times.get(&target).cloned());
+                started.insert(node, Instant::now());
+
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    let mut log = Log::new(buffered);
+                    let result = recipe.execute(vars, settings, &mut log);
+                    drop(sender.send((node, result, log)));
+                });
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            let (node, result, mut log) = match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(finished) => finished,
+                Err(_) => {
+                    progress.draw(); // keep the eta counting down
+                    continue;
+                }
+            };
+            let recipe = &self.graph[node];
+            running -= 1;
+            progress.finish(&recipe.target());
+
+            if let Some(pool) = &recipe.pool {
+                *pools.entry(pool.as_str()).or_default() -= 1;
+            }
+
+            let time = started[&node].elapsed();
+
+            let outcome = match result {
+                Ok(outcome) => outcome,
+                Err(failure) => {
+                    log.print(&format!("{}: {}\n", recipe.target().red(), failure.message));
+                    report.push(recipe.target(), time, Verdict::Failed(failure));
+
+                    if let Some(text) = log.buffer {
+                        blocks.add(recipe.target(), text);
+                    }
+                    continue;
+                }
+            };
+            match outcome.cached {
+                true => {
+                    log.print(&format!("{} {}\n", "cached".mint(), recipe.target()));
+                    report.push(recipe.target(), time, Verdict::Cached);
+                }
+                false => {
+                    state.times.insert(recipe.target(), time);
+                    report.push(recipe.target(), time, Verdict::Built);
+                }
+            }
+            if outcome.attempts > 1 {
+                report.retried.push((recipe.target(), outcome.attempts));
+            }
+            if let Some(deps) = outcome.deps {
+                state.deps.insert(recipe.target(), deps);
+            }
+
+            for read in outcome.reads {
+                if self.undeclared(recipe, &read) {
+                    let target = recipe.target();
+                    let message = "reads a file built by another recipe without declaring it";
+                    let target = target.blue();
+                    log.print(&format!(
+                        "{}: {} {}: {}\n",
+                        "Audit".yellow(),
+                        target,
+                        message,
+                        read.red()
+                    ));
+                    report.undeclared += 1;
+                }
+            }
+
+            if let Some(text) = log.buffer {
+                blocks.add(recipe.target(), text);
+            }
+            self.finish(node, &mut pending, &mut ready);
+        });
+        blocks.flush();
+        progress.clear();
+
+        let finished: HashSet<_> = report.cases.iter().map(|case| case.target.clone()).collect();
+        for &node in targets.iter().sorted() {
+            let target = self.graph[node].target();
+            if !finished.contains(&target) {
+                let verdict = match stale.contains(&node) {
+                    true => Verdict::Skipped,
+                    false => Verdict::Fresh, // already known to be up to date
+                };
+                report.push(target, Duration::ZERO, verdict);
+            }
+        }
+
+        report.wall = wall.elapsed();
+        report.cpu = cpu_time().saturating_sub(cpu);
+        report
+    }
+
+    /// The targets that need to run: those outdated themselves and everything built from them.
+    /// A recipe whose dependencies don't run sees the same files later, so this can be known upfront.
+    fn stale(&self, targets: &HashSet<NodeIndex>) -> HashSet<NodeIndex> {
+        let mut stale = HashSet::new();
+
+        // cycles were ruled out when the graph was made
+        let order = petgraph::algo::toposort(&self.graph, None).unwrap_or_default();
+
+        for node in order.into_iter().rev().filter(|node| targets.contains(node)) {
+            // only normal dependencies cause rebuilds
+            let mut deps = self.graph.edges_directed(node, Direction::Outgoing);
+            let rebuilt = deps
+                .any(|edge| *edge.weight() == Dependency::Normal && stale.contains(&edge.target()));
+
+            if rebuilt || self.graph[node].outdated() {
+                stale.insert(node);
+            }
+        }
+        stale
+    }
+
+    /// The stale targets in an order that depends only on the hayfile, dependencies first
+    fn order(&self, stale: &HashSet<NodeIndex>) -> Vec<String> {
+        let order = petgraph::algo::toposort(&self.graph, None).unwrap_or_default();
+        let order = order.into_iter().rev().filter(|node| stale.contains(node));
+        order.map(|node| self.graph[node].target()).collect()
+    }
+
+    /// Marks a recipe as done, readying the dependents that were only waiting on it
+    fn finish(
+        &self,
+        node: NodeIndex,
+        pending: &mut BTreeMap<NodeIndex, usize>,
+        ready: &mut VecDeque<NodeIndex>,
+    ) {
+        let dependents = self.graph.neighbors_directed(node, Direction::Incoming).unique();
+        for dependent in dependents {
+            if let Some(count) = pending.get_mut(&dependent) {
+                *count -= 1;
+                if *count == 0 {
+                    pending.remove(&dependent);
+                    ready.push_back(dependent);
+                }
+            }
+        }
+    }
+
+    fn undeclared(&self, recipe: &Recipe, read: &String) -> bool {
+        let rule = &recipe.rule;
+        let mut declared = rule.steps.iter().flatten().chain(&rule.order).chain(&rule.outputs);
+        self.nodes.contains_key(read) && !declared.any(|x| x == read)
+    }
+}
+
+impl Report {
+    fn push(&mut self, target: String, time: Duration, verdict: Verdict) {
+        self.cases.push(Case {
+            target,
+            time,
+            verdict,
+        });
+    }
+
+    fn count(&self, verdict: fn(&Verdict) -> bool) -> usize {
+        self.cases.iter().filter(|case| verdict(&case.verdict)).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|verdict| matches!(verdict, Verdict::Failed(_)))
+    }
+
+    pub fn print(&self) {
+        let built = self.count(|verdict| matches!(verdict, Verdict::Built));
+        let fresh = self.count(|verdict| matches!(verdict, Verdict::Fresh));
+        let cached = self.count(|verdict| matches!(verdict, Verdict::Cached));
+        let skipped = self.count(|verdict| matches!(verdict, Verdict::Skipped));
+        let failed = self.failed();
+
+        if built + cached + failed + skipped == 0 {
+            println!("{}", "nothing to be done".yellow());
+        } else {
+            let counts = [
+                (built, "built".mint()),
+                (fresh, "up to date".clear()),
+                (cached, "cached".mint()),
+                (failed, "failed".red()),
+                (skipped, "skipped".yellow()),
+            ];
+            let counts = counts.iter().filter(|(count, _)| *count > 0);
+            println!(
+                "{}",
+                counts.map(|(count, what)| format!("{} {}", count, what)).join(", ")
+            );
+
+            let times = self
+                .cases
+                .iter()
+                .filter(|case| matches!(case.verdict, Verdict::Built));
+            let slowest = times.sorted_by_key(|case| std::cmp::Reverse(case.time));
+            for case in slowest.take(SLOWEST).filter(|_| built > 1) {
+                println!("{:>8.2}s {}", case.time.as_secs_f64(), case.target.blue());
+            }
+
+            let (wall, cpu) = (self.wall.as_secs_f64(), self.cpu.as_secs_f64());
+            println!("{}", format!("{:.2}s wall, {:.2}s cpu", wall, cpu).grey());
+        }
+
+        for (target, attempts) in &self.retried {
+            println!("{} {} after {} attempts", "flaky".yellow(), target.blue(), attempts);
+        }
+
+        if self.undeclared > 0 {
+            let reads = "undeclared dependency".plural(self.undeclared);
+            println!("{} found {} {}", "Audit".yellow(), self.undeclared, reads);
+        }
+    }
+}
+
+/// The processor time used so far by haymaker and the commands it has waited on
+fn cpu_time() -> Duration {
+    let mut total = Duration::ZERO;
+
+    for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(who, &mut usage) } == 0 {
+            for time in [usage.ru_utime, usage.ru_stime] {
+                total += Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+            }
+        }
+    }
+    total
+}
+
+#[test]
+fn test_propagation() {
+    let (hay, _) = crate::load(Path::new("tests/propagate.hay"), &VarMap::new());
+    let graph = Graph::new(hay.recipes, hay.pools, &State::default()).unwrap();
+    let inherited = |target: &str| {
+        let recipe = &graph.graph[graph.nodes[target]];
+        recipe.inherited.iter().map(|var| var.name.clone()).collect_vec()
+    };
+
+    assert_eq!(inherited("main.o"), vec!["mode"]);
+    assert!(inherited("build").is_empty());
+}
@@ -0,0 +1,89 @@
+//
+// Haymaker
+//
+
+use crate::graph::{Report, Verdict};
+use crate::regexes;
+
+use std::path::Path;
+
+/// Writes the report as JUnit XML, with one testcase per target
+pub fn write(path: &Path, report: &Report) -> std::io::Result<()> {
+    let failures = report.failed();
+    let skipped = report.cases.iter().filter(|case| skipped(&case.verdict)).count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += "<testsuites>\n";
+    xml += &format!(
+        "  <testsuite name=\"haymaker\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        report.cases.len(),
+        failures,
+        skipped,
+        report.wall.as_secs_f64()
+    );
+
+    for case in &report.cases {
+        let name = escape(&case.target);
+        let time = case.time.as_secs_f64();
+        let open = format!(
+            "    <testcase classname=\"haymaker\" name=\"{}\" time=\"{:.3}\"",
+            name, time
+        );
+
+        match &case.verdict {
+            Verdict::Built | Verdict::Cached => xml += &format!("{}/>\n", open),
+            Verdict::Fresh => {
+                xml += &format!("{}>\n      <skipped message=\"up to date\"/>\n", open);
+                xml += "    </testcase>\n";
+            }
+            Verdict::Skipped => {
+                xml += &format!("{}>\n      <skipped message=\"a dependency failed\"/>\n", open);
+                xml += "    </testcase>\n";
+            }
+            Verdict::Failed(failure) => {
+                let message = escape(&failure.message);
+                let command = escape(failure.command.as_deref().unwrap_or_default());
+                xml += &format!("{}>\n", open);
+                xml += &format!("      <failure message=\"{}\">{}</failure>\n", message, command);
+                if !failure.stderr.is_empty() {
+                    xml += &format!("      <system-err>{}</system-err>\n", escape(&failure.stderr));
+                }
+                xml += "    </testcase>\n";
+            }
+        }
+    }
+
+    xml += "  </testsuite>\n";
+    xml += "</testsuites>\n";
+    std::fs::write(path, xml)
+}
+
+fn skipped(verdict: &Verdict) -> bool {
+    matches!(verdict, Verdict::Fresh | Verdict::Skipped)
+}
+
+/// Escapes text for XML, dropping the control characters it can't hold, such as color codes
+fn escape(text: &str) -> String {
+    let text = regexes::COLOR.replace_all(text, "");
+    let mut escaped = String::new();
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => continue,
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_junit() {
+    assert_eq!(escape("a < b && \"c\""), "a &lt; b &amp;&amp; &quot;c&quot;");
+    assert_eq!(escape("\x1b[31;1merror\x1b[0;0m: bad\n"), "error: bad\n");
+}
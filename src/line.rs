@@ -4,21 +4,20 @@
 
 #[derive(Clone, Debug, Default)]
 pub struct LineInfo<'a> {
-    pub line: &'a str,
     pub sans_flags: &'a str,
     pub full_line: &'a str,
     pub shell: bool,
     pub debug: bool,
     pub silence: bool,
     pub neglect: bool,
-    pub split: usize,
+    pub split: usize, // the byte offset where the flags end
 }
 
 impl<'a> From<&'a str> for LineInfo<'a> {
     fn from(line: &'a str) -> Self {
         let mut info = LineInfo::default();
 
-        for (index, (offset, c)) in line.char_indices().enumerate() {
+        for (offset, c) in line.char_indices() {
             if c.is_whitespace() {
                 info.shell = true;
                 continue;
@@ -29,7 +28,7 @@ impl<'a> From<&'a str> for LineInfo<'a> {
                 '^' => info.neglect = true,
                 _ => {
                     info.sans_flags = &line[offset..];
-                    info.split = index;
+                    info.split = offset;
                     break;
                 }
             }
@@ -39,6 +38,80 @@ impl<'a> From<&'a str> for LineInfo<'a> {
     }
 }
 
+/// A line as the hayfile means it, joined from physical lines ending in a backslash
+#[derive(Clone, Debug, Default)]
+pub struct Joined {
+    pub text: String,
+    pieces: Vec<Piece>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Piece {
+    start: usize,   // where the physical line's text starts in the joined text
+    skipped: usize, // the leading whitespace dropped from a continuation
+    lineno: usize,
+    source: String,
+}
+
+impl Joined {
+    /// The number of the first physical line
+    pub fn lineno(&self) -> usize {
+        self.pieces.first().map_or(0, |piece| piece.lineno)
+    }
+
+    /// Finds the physical line holding a byte offset into the joined text,
+    /// giving that line, its number and the column of the offset within it
+    pub fn locate(&self, offset: usize) -> (&str, usize, usize) {
+        let piece = self.pieces.iter().rev().find(|piece| piece.start <= offset);
+        let piece = piece.unwrap_or(&self.pieces[0]);
+        let offset = piece.skipped + offset.saturating_sub(piece.start);
+        let column = piece.source.get(..offset).map_or(offset, |text| text.chars().count());
+        (&piece.source, piece.lineno, column)
+    }
+}
+
+/// Joins lines ending in a backslash with the ones after them, separated by a space
+pub fn join(lines: Vec<String>) -> Vec<Joined> {
+    let mut joined: Vec<Joined> = vec![];
+    let mut continuing = false;
+
+    for (index, source) in lines.into_iter().enumerate() {
+        let trimmed = source.trim_end();
+        let (text, continues) = match trimmed.strip_suffix('\\') {
+            Some(text) => (text.trim_end(), true),
+            None => (source.as_str(), false),
+        };
+
+        match joined.last_mut() {
+            Some(last) if continuing => {
+                let after = text.trim_start();
+                last.pieces.push(Piece {
+                    start: last.text.len(),
+                    skipped: text.len() - after.len(),
+                    lineno: index + 1,
+                    source: source.clone(),
+                });
+                last.text += after;
+            }
+            _ => joined.push(Joined {
+                text: text.to_string(),
+                pieces: vec![Piece {
+                    start: 0,
+                    skipped: 0,
+                    lineno: index + 1,
+                    source: source.clone(),
+                }],
+            }),
+        }
+
+        if continues {
+            joined.last_mut().unwrap().text.push(' ');
+        }
+        continuing = continues;
+    }
+    joined
+}
+
 #[test]
 fn test_lines() {
     #[rustfmt::skip]
@@ -57,3 +130,25 @@ fn test_lines() {
         assert_eq!(line.sans_flags, sans_flags);
     }
 }
+
+#[test]
+fn test_continuation() {
+    let lines = ["a: b \\", "    c\\", "  d", "\techo \\", "\t\thi"];
+    let joined = join(lines.iter().map(|line| line.to_string()).collect());
+
+    assert_eq!(joined.len(), 2);
+    assert_eq!(joined[0].text, "a: b c d");
+    assert_eq!(joined[1].text, "\techo hi");
+    assert_eq!(joined[0].lineno(), 1);
+    assert_eq!(joined[0].locate(3), ("a: b \\", 1, 3));
+    assert_eq!(joined[0].locate(7), ("  d", 3, 2));
+    assert_eq!(joined[1].locate(6), ("\t\thi", 5, 2));
+
+    // offsets count bytes, while columns count characters
+    let lines = ["v := ééééé \\", "   @(a | drop x)"];
+    let joined = join(lines.iter().map(|line| line.to_string()).collect());
+
+    assert_eq!(joined[0].text, "v := ééééé @(a | drop x)");
+    assert_eq!(joined[0].locate(7), ("v := ééééé \\", 1, 6));
+    assert_eq!(joined[0].locate(16), ("   @(a | drop x)", 2, 3));
+}
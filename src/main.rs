@@ -2,77 +2,273 @@
 // Haymaker
 //
 
+use crate::cache::Cache;
 use crate::comments::uncomment;
+use crate::condition::Conditionals;
+use crate::config::Config;
 use crate::console::Color;
-use crate::derive::{add_derivation_highlights, derive, VarMap};
-use crate::line::LineInfo;
+use crate::derive::{add_derivation_highlights, assign, derive, Assignment, Flavor, Var, VarMap};
+use crate::diagnostic::Diagnostics;
+use crate::graph::Graph;
+use crate::line::{join, Joined, LineInfo};
 use crate::parsed::MakeLine;
-use crate::recipe::Recipe;
+use crate::recipe::{Buffer, Recipe, Settings};
+use crate::remote::Remote;
+use crate::state::State;
 use crate::text::Text;
+use crate::watch::Watcher;
 
 use itertools::Itertools;
-use petgraph::{stable_graph::StableGraph, Direction};
+use petgraph::stable_graph::NodeIndex;
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
-use lalrpop_util::lalrpop_mod;
-//use lalrpop_util::ParseError;
 use crate::def::DefParser;
-lalrpop_mod!(def);
+use lalrpop_util::lalrpop_mod;
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    def
+);
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    shell
+);
 
+mod ast;
+mod cache;
 mod comments;
+mod condition;
+mod config;
 mod console;
+mod daemon;
+mod depfile;
 mod derive;
+mod diagnostic;
+mod graph;
+mod junit;
 mod line;
 mod parsed;
+mod progress;
 mod recipe;
 mod regexes;
+mod remote;
+mod sandbox;
+mod signals;
+mod state;
 mod text;
+mod trace;
+mod watch;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "haymaker", about = "A fearlessly parallel build system")]
 struct Opt {
-    #[structopt(parse(from_os_str))]
+    /// The hayfile to build, rather than the one in the current directory
+    #[structopt(short = "f", long = "file", parse(from_os_str))]
     hayfile: Option<PathBuf>,
+
+    /// The targets to build, or every target when none are given, and any name=value overrides.
+    /// A first goal naming a file is the hayfile instead, as it was before goals existed,
+    /// while goals after -- are always targets, even when named like a file or subcommand
+    goals: Vec<String>,
+
+    /// How many recipes to run at once, defaulting to one so that output isn't interleaved
+    #[structopt(short = "j", long)]
+    jobs: Option<usize>,
+
+    /// Print each recipe's output as one block, in order of completion or in a stable order
+    #[structopt(long, value_name = "completion|stable")]
+    buffer: Option<Buffer>,
+
+    /// Keep running, rebuilding whenever a source or the hayfile changes
+    #[structopt(long)]
+    watch: bool,
+
+    /// Run each recipe in a scratch directory holding only its declared inputs.
+    /// Only the working directory changes, so undeclared files are still readable
+    /// through absolute paths or paths leading out of it, such as ../file
+    #[structopt(long)]
+    hermetic: bool,
+
+    /// Trace the files each recipe reads and report undeclared dependencies
+    #[structopt(long)]
+    audit: bool,
+
+    /// Restore outputs from the build cache, and store them there after building.
+    /// Recipes' commands are derived before the first one runs, since they make up the key,
+    /// so subcalls can't read files made by earlier commands
+    #[structopt(long)]
+    cache: bool,
+
+    /// Share the build cache through an http content store, overriding the user's config
+    #[structopt(long, value_name = "url")]
+    remote_cache: Option<String>,
+
+    /// Fetch from the remote cache without ever uploading to it
+    #[structopt(long)]
+    remote_read_only: bool,
+
+    /// Write what happened to each target as JUnit XML
+    #[structopt(long, value_name = "report.xml", parse(from_os_str))]
+    junit: Option<PathBuf>,
+
+    /// Load the hayfile directly, even when a daemon is running
+    #[structopt(long)]
+    no_daemon: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
 }
 
-fn main() {
-    let opt = Opt::from_args();
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Manage the build cache
+    Cache(CacheCommand),
 
-    let hayfile = match opt.hayfile {
-        Some(hayfile) => hayfile,
-        None => {
-            let defaults = ["hayfile", "Hayfile", "makefile", "Makefile"];
+    /// Manage a background server that keeps hayfiles loaded between runs
+    Daemon(DaemonCommand),
+}
 
-            match defaults.into_iter().find(|file| Path::new(file).exists()) {
-                Some(hayfile) => Path::new(hayfile).to_path_buf(),
-                None => {
-                    println!("No {} in current directory", "hayfile".red());
-                    std::process::exit(1);
-                }
-            }
-        }
+#[derive(Debug, StructOpt)]
+enum DaemonCommand {
+    /// Start the server in the background
+    Start,
+
+    /// Stop the server
+    Stop,
+
+    /// Run the server in the foreground
+    Serve,
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// Show how much is cached
+    Stats,
+
+    /// Remove old entries
+    Gc {
+        /// Remove entries unused for this many days
+        #[structopt(long, default_value = "30")]
+        max_age: u64,
+
+        /// Remove the oldest entries until the cache is smaller than this, like 500M or 2G
+        #[structopt(long, parse(try_from_str = cache::parse_size))]
+        max_size: Option<u64>,
+    },
+}
+
+fn open_cache(opt: &Opt) -> Cache {
+    let config = Config::load();
+    let cache = Cache::new(Cache::default_dir());
+
+    let url = match opt.remote_cache.as_ref().or(config.remote_cache.as_ref()) {
+        Some(url) => url,
+        None => return cache,
     };
+    let read_only = opt.remote_read_only || config.remote_read_only;
 
-    let filename = hayfile.to_string_lossy();
-    let haysource = match std::fs::read_to_string(&hayfile) {
-        Ok(haysource) => haysource,
+    match Remote::new(url, read_only) {
+        Ok(remote) => cache.with_remote(remote),
         Err(err) => {
-            println!("Could not open {}\n{}", filename.red(), err);
-            std::process::exit(1);
+            println!("{}: {}", "Cache".yellow(), err);
+            cache
         }
+    }
+}
+
+/// Everything read from a hayfile
+struct Hayfile {
+    recipes: Vec<Recipe>,
+    vars: VarMap,
+    pools: BTreeMap<String, usize>, // how many recipes may run at once in each pool
+    files: Vec<PathBuf>,            // the hayfile and its includes
+}
+
+/// A define block being read, up to its end
+struct Define {
+    name: String,
+    params: Vec<String>,
+    lines: Vec<String>,
+    depth: usize, // how many defines and ifs are open inside it, since they also end with end
+    start: (String, usize, usize), // the define's line, number and column, for errors
+}
+
+/// Loads a hayfile and what it includes,
+/// with variables from the command line that its assignments can't change,
+/// along with every problem found along the way
+fn load(hayfile: &Path, overrides: &VarMap) -> (Hayfile, Diagnostics) {
+    let mut hay = Hayfile {
+        recipes: vec![],
+        vars: overrides.clone(),
+        pools: BTreeMap::new(),
+        files: vec![hayfile.to_path_buf()],
     };
+    let mut target_vars = vec![];
+    let mut diagnostics = Diagnostics::default();
+    match std::fs::read_to_string(hayfile) {
+        Ok(haysource) => {
+            let mut reading = vec![];
+            read(
+                hayfile,
+                &haysource,
+                &mut hay,
+                &mut target_vars,
+                &mut reading,
+                &mut diagnostics,
+            );
+        }
+        Err(err) => {
+            let filename = hayfile.to_string_lossy();
+            let message = format!("could not read {}: {}", filename.red(), err);
+            diagnostics.file_error("Hayfile", &message, &filename);
+        }
+    }
 
-    let mut recipes: Vec<Recipe> = vec![];
-    let mut vars = VarMap::new();
-    let lines = uncomment(&haysource, "");
+    for (target, var, (filename, source, lineno, offset)) in target_vars {
+        let recipe = hay
+            .recipes
+            .iter_mut()
+            .find(|recipe| recipe.rule.outputs.contains(&target));
+        match recipe {
+            Some(recipe) => recipe.vars.push(var),
+            None => {
+                let message = format!("no recipe makes {}", target.red());
+                diagnostics.source_error("Variable", &message, &filename, &source, lineno, offset);
+            }
+        }
+    }
+    (hay, diagnostics)
+}
 
-    for (index, line) in lines.into_iter().enumerate() {
+/// A target's own variable, with where it was assigned
+type TargetVar = (String, Assignment, (String, String, usize, usize));
+
+/// Reads a hayfile's text into what's been loaded so far, including other files as it goes.
+/// The files being read, each included by the one before, are kept to catch include cycles.
+fn read(
+    hayfile: &Path,
+    haysource: &str,
+    hay: &mut Hayfile,
+    target_vars: &mut Vec<TargetVar>,
+    reading: &mut Vec<PathBuf>,
+    diagnostics: &mut Diagnostics,
+) {
+    let filename = hayfile.to_string_lossy();
+
+    let mut conditionals = Conditionals::default();
+    let mut defining: Option<Define> = None;
+    let mut orphaned = false; // whether the last rule couldn't be read, leaving its commands
+    let mut current: Option<usize> = None; // the recipe shell lines belong to, reset by includes
+    let lines = uncomment(haysource, "");
+    reading.push(hayfile.to_path_buf());
+
+    for joined in join(lines) {
         // Hayfiles are context-sensitive, so we must determine how to handle each line
 
-        let lineno = index + 1;
-        let source = &line;
+        let line = &joined.text;
 
         if line.trim() == "" {
             // skip blanks for performance
@@ -80,149 +276,839 @@ fn main() {
         }
 
         let info = LineInfo::from(line.as_ref());
+        let raw_line = line;
         let line = info.sans_flags.trim();
 
+        if let Some(define) = &mut defining {
+            // define blocks keep their lines as they are, up to the matching end
+
+            // ifs end with end as well, though an else if continues one rather than opening it
+            let condition = regexes::CONDITION.captures(line);
+            let opens = regexes::DEFINE.is_match(line)
+                || condition.is_some_and(|caps| caps.get(1).is_none());
+            let ends = !info.shell && line == "end" && define.depth == 0;
+            match (info.shell, line) {
+                (false, "end") => define.depth = define.depth.saturating_sub(1),
+                (false, _) if opens => define.depth += 1,
+                _ => {}
+            }
+            if !ends {
+                define.lines.push(raw_line.to_string());
+                continue;
+            }
+
+            let define = defining.take().unwrap();
+            let overridden = hay
+                .vars
+                .get(&define.name)
+                .is_some_and(|var| var.flavor == Flavor::Override);
+            if conditionals.active() && !overridden {
+                let mut var = Var::new(define.lines.join("\n"), Flavor::Define);
+                var.params = define.params;
+                hay.vars.insert(define.name, var);
+            }
+            continue;
+        }
+
+        if !info.shell {
+            if let Some(caps) = regexes::DEFINE.captures(line) {
+                let start = joined.locate(info.split);
+                defining = Some(Define {
+                    name: caps[1].to_string(),
+                    params: caps[2].split_whitespace().map(String::from).collect(),
+                    lines: vec![],
+                    depth: 0,
+                    start: (start.0.to_string(), start.1, start.2),
+                });
+                continue;
+            }
+
+            // conditionals, which decide whether the lines up to their end are kept
+
+            let branch = match regexes::CONDITION.captures(line) {
+                Some(caps) => {
+                    let chained = caps.get(1).is_some();
+                    let expression = caps.get(2).unwrap();
+
+                    let decides = match chained {
+                        true => conditionals.undecided(),
+                        false => conditionals.active(),
+                    };
+                    let holds = match decides {
+                        true => condition::evaluate(expression.as_str(), &mut hay.vars, info.debug),
+                        false => Ok(false),
+                    };
+                    let holds = holds.unwrap_or_else(|err| {
+                        let offset = info.split + expression.start();
+                        derive_error(diagnostics, &err, &filename, &joined, &info, offset);
+                        false
+                    });
+
+                    match chained {
+                        true => Some(conditionals.otherwise(Some(holds))),
+                        false => {
+                            conditionals.open(holds, joined.locate(info.split));
+                            Some(Ok(()))
+                        }
+                    }
+                }
+                None if line == "else" => Some(conditionals.otherwise(None)),
+                None if line == "end" => Some(conditionals.end()),
+                None => None,
+            };
+
+            if let Some(result) = branch {
+                if let Err(message) = result {
+                    let (source, lineno, offset) = joined.locate(info.split);
+                    diagnostics.source_error(
+                        "Conditional",
+                        &message,
+                        &filename,
+                        source,
+                        lineno,
+                        offset,
+                    );
+                }
+                continue;
+            }
+        }
+
+        if !conditionals.active() {
+            continue;
+        }
+
         if info.shell {
             // shell source can have arbitrary text & starts after the tab
 
-            let recipe = match recipes.last_mut() {
+            if orphaned {
+                continue; // the rule's error was already reported
+            }
+
+            let recipe = match current.map(|index| &mut hay.recipes[index]) {
                 Some(recipe) => recipe,
                 None => {
                     let kind = "Structure";
                     let message = "stray shell code outside of a recipe";
-                    console::print_source_error(
-                        kind, &message, &filename, &source, lineno, info.split,
-                    );
-                    std::process::exit(1);
+                    let (source, lineno, offset) = joined.locate(info.split);
+                    diagnostics.source_error(kind, message, &filename, source, lineno, offset);
+                    continue;
                 }
             };
 
+            if let Some(caps) = regexes::ATTRIBUTE.captures(line) {
+                // attributes start with a dot, so that commands like depfile=x cmd still run
+                if let Err(message) = recipe.set_attribute(&caps[1], caps[2].trim().to_string()) {
+                    // point at the name when there's no such attribute, and otherwise its value
+                    let group = match recipe::ATTRIBUTES.contains(&&caps[1]) {
+                        true => 2,
+                        false => 1,
+                    };
+                    let offset = info.split + caps.get(group).map_or(0, |part| part.start());
+                    let (source, lineno, offset) = joined.locate(offset);
+                    diagnostics.source_error(
+                        "Attribute",
+                        &message,
+                        &filename,
+                        source,
+                        lineno,
+                        offset,
+                    );
+                }
+                continue;
+            }
+
+            if let Some(caps) = regexes::RECIPE_ASSIGNMENT.captures(line) {
+                // the target's own variables, which start with var
+                // so that commands like name = value or NAME=value cmd still run
+                recipe.vars.push(target_assignment(&caps));
+                continue;
+            }
+
             recipe.add_command(line.to_string(), info.debug);
             continue;
         }
 
+        if let Some(caps) = regexes::POOL.captures(line) {
+            // pools, which must come before assignments since both use =
+
+            match caps[2].trim().parse() {
+                Ok(depth) if depth > 0 => drop(hay.pools.insert(caps[1].to_string(), depth)),
+                _ => {
+                    let message =
+                        format!("pool {} needs a positive depth", caps[1].to_string().red());
+                    let offset = info.split + caps.get(2).map_or(0, |depth| depth.start());
+                    let (source, lineno, offset) = joined.locate(offset);
+                    diagnostics.source_error("Pool", &message, &filename, source, lineno, offset);
+                }
+            }
+            continue;
+        }
+
+        if let Some(caps) = regexes::TARGET_ASSIGNMENT.captures(line) {
+            // variables of particular targets, which may be defined further down
+
+            let targets = match derive(&caps["targets"], &mut hay.vars, info.debug) {
+                Ok(targets) => targets,
+                Err(err) => {
+                    derive_error(diagnostics, &err, &filename, &joined, &info, info.split);
+                    continue;
+                }
+            };
+
+            let var = target_assignment(&caps);
+            let location = joined.locate(info.split);
+            let location = (location.0.to_string(), location.1, location.2);
+            for target in targets.split_whitespace() {
+                let location = (filename.to_string(), location.0.clone(), location.1, location.2);
+                target_vars.push((target.to_string(), var.clone(), location));
+            }
+            continue;
+        }
+
+        if let Some(caps) = regexes::ASSIGNMENT.captures(line) {
+            // assignments that append, fill in undefined variables, or derive right away
+
+            let flavor = Flavor::from_operator(&caps[2]).unwrap();
+            let value = caps.get(3).unwrap();
+            let assigns = regexes::VAR.captures_iter(&caps[1]).map(|x| x[0].to_string());
+
+            let assigns = assigns.collect_vec();
+            let assigned =
+                assign(&mut hay.vars, &assigns, flavor, value.as_str().trim(), info.debug);
+
+            if let Err(err) = assigned {
+                let part = value.as_str().trim_start();
+                let offset = value.end() - part.len();
+                let offset = info.split + offset;
+                derive_error(diagnostics, &err, &filename, &joined, &info, offset);
+            }
+            continue;
+        }
+
         if line.contains("=") {
-            // variable assignments
+            // variable assignments, which may be chained as in a = b = value
 
-            let sides = line.split('=').into_iter().rev();
+            let sides = line.split('=').rev();
 
             for (value, dest) in sides.tuple_windows() {
                 let value = value.trim();
                 let assigns = regexes::VAR.captures_iter(dest).map(|x| x[0].to_string());
+                let assigns = assigns.collect_vec();
 
-                for assign in assigns {
-                    vars.insert(assign, value.to_string());
-                }
+                // plain values are derived on use, so assigning them can't fail
+                let _ = assign(&mut hay.vars, &assigns, Flavor::Recursive, value, info.debug);
             }
             continue;
         }
 
-        let raw = line.clone();
-        let line = match derive(&line, &mut vars, info.debug) {
+        let raw = line;
+        let line = match derive(line, &mut hay.vars, info.debug) {
             Ok(line) => line,
-            Err(message) => {
-                let note = format!("{}: {} {}", "note".white(), "this was", raw.grey());
-                let help = format!(
-                    "{}: place a {} before the line to enable debug mode",
-                    "help".white(),
-                    "+".mint()
-                );
-
-                let more = match info.debug {
-                    true => vec![note],
-                    false => vec![note, help],
-                };
-                let kind = "Subcall";
-                console::print_processed_error(
-                    kind, &message, &filename, &line, more, lineno, 0,
-                );
-                
-                if !info.neglect {
-                    std::process::exit(1);
-                }
+            Err(err) => {
+                derive_error(diagnostics, &err, &filename, &joined, &info, info.split);
+                orphaned = true;
                 continue;
             }
         };
 
-        if line.starts_with("include") {
-            //
+        if line.split_whitespace().next() == Some("include") {
+            // includes, where -include skips files that don't exist
+
+            let report = |diagnostics: &mut Diagnostics, message: String, offset: usize| {
+                let kind = "Include";
+                line_error(
+                    diagnostics,
+                    kind,
+                    &message,
+                    &filename,
+                    &joined,
+                    &info,
+                    raw,
+                    &line,
+                    offset,
+                );
+            };
 
             let mut includes = line.split_when_balanced_with_offsets(' ', '\'').into_iter();
-            includes.next(); // discard the "import"
-
-            for (mut offset, include) in includes {
-                //
-
-                if !Path::new(include).exists() {
-                    let kind = "Include";
-                    let message = format!("file {} does not exist", include.red());
-
-                    if line == raw {
-                        offset += info.split;
-                        console::print_source_error(
-                            kind, &message, &filename, &source, lineno, offset,
-                        );
-                    } else {
-                        let note = format!("{}: {} {}", "note".white(), "this was", raw.grey());
-                        let help = format!(
-                            "{}: place a {} before the line to enable debug mode",
-                            "help".white(),
-                            "+".mint()
-                        );
-
-                        let info = match info.debug {
-                            true => vec![note],
-                            false => vec![note, help],
-                        };
-
-                        console::print_processed_error(
-                            kind, &message, &filename, &line, info, lineno, offset,
-                        );
-                    }
+            includes.next(); // discard the "include"
+
+            for (offset, include) in includes {
+                // includes are found next to the file including them
+                let include = include.trim_matches('\'');
+                let path = match hayfile.parent() {
+                    Some(dir) => dir.join(include),
+                    None => PathBuf::from(include),
+                };
+
+                // patterns, quoted so that a /* doesn't start a comment, include each file
+                // they match in sorted order, and are watched through the directory
+                // holding them so that new matches are noticed
+                let paths = match include.contains(['*', '?', '[']) {
+                    true => {
+                        let pattern = |dir: &Path| dir.to_string_lossy().contains(['*', '?', '[']);
+                        let dir = path.ancestors().skip(1).find(|dir| !pattern(dir));
+                        hay.files.extend(dir.map(Path::to_path_buf));
 
-                    if !info.neglect {
-                        std::process::exit(1);
+                        match glob::glob(&path.to_string_lossy()) {
+                            Ok(paths) => {
+                                paths.flatten().filter(|path| path.is_file()).sorted().collect_vec()
+                            }
+                            Err(err) => {
+                                let message =
+                                    format!("{} is not a pattern: {}", include.red(), err.msg);
+                                report(diagnostics, message, offset);
+                                continue;
+                            }
+                        }
                     }
+                    false => vec![path],
+                };
+
+                if paths.is_empty() && !info.silence {
+                    report(diagnostics, format!("no files match {}", include.red()), offset);
+                }
+
+                for path in paths {
+                    hay.files.push(path.clone());
+
+                    let canonical = path.canonicalize().ok();
+                    let cycle = reading.iter().any(|file| file.canonicalize().ok() == canonical);
+
+                    let message = match canonical {
+                        None if info.silence => continue,
+                        None => format!("file {} does not exist", include.red()),
+                        Some(_) if cycle => {
+                            let files =
+                                reading.iter().chain([&path]).map(|file| file.to_string_lossy());
+                            let files = files.collect_vec().join(", ");
+                            format!("include cycle between {}", files.red())
+                        }
+                        // files that can't be read, such as directories, are reported here
+                        Some(_) => match std::fs::read_to_string(&path) {
+                            Ok(source) => {
+                                read(&path, &source, hay, target_vars, reading, diagnostics);
+                                continue;
+                            }
+                            Err(err) => format!("could not read {}: {}", include.red(), err),
+                        },
+                    };
+                    report(diagnostics, message, offset);
                 }
             }
+            current = None;
             continue;
         }
 
         let parsed = match DefParser::new().parse(&line) {
             Ok(Some(parsed)) => parsed,
-            Err(err) => panic!("error parsing\n{}\n{}", line.red(), err),
-            Ok(_) => continue,
+            Ok(None) => continue,
+            Err(err) => {
+                let (message, offset) = parsed::describe_error(err, &line);
+                line_error(
+                    diagnostics,
+                    "Parse",
+                    &message,
+                    &filename,
+                    &joined,
+                    &info,
+                    raw,
+                    &line,
+                    offset,
+                );
+                orphaned = true;
+                continue;
+            }
         };
 
         let MakeLine::Rule(rule) = parsed;
         let recipe = Recipe::from(rule);
-        recipes.push(recipe);
+        hay.recipes.push(recipe);
+        current = Some(hay.recipes.len() - 1);
+        orphaned = false;
+    }
+
+    if let Some(define) = defining {
+        let (source, lineno, offset) = define.start;
+        let message = "this define is never ended";
+        diagnostics.source_error("Define", message, &filename, &source, lineno, offset);
+    }
+
+    if let Some((source, lineno, offset)) = conditionals.unclosed() {
+        let message = "this if is never ended";
+        diagnostics.source_error("Conditional", message, &filename, source, *lineno, *offset);
+    }
+
+    reading.pop();
+}
+
+/// Reads an assignment to one of a target's own variables
+fn target_assignment(caps: &regex::Captures) -> Assignment {
+    Assignment {
+        name: caps["name"].to_string(),
+        flavor: Flavor::from_operator(&caps["operator"]).unwrap(),
+        value: caps["value"].trim().to_string(),
+        propagate: caps.name("propagate").is_some(),
+    }
+}
+
+/// Records an error at a byte offset into a derived line, pointing into the hayfile
+/// when deriving left it alone, and otherwise showing the text it came from
+#[allow(clippy::too_many_arguments)]
+fn line_error(
+    diagnostics: &mut Diagnostics,
+    kind: &str,
+    message: &str,
+    filename: &str,
+    joined: &Joined,
+    info: &LineInfo,
+    raw: &str,
+    line: &str,
+    offset: usize,
+) {
+    if line == raw {
+        let (source, lineno, offset) = joined.locate(info.split + offset);
+        let found = diagnostics.source_error(kind, message, filename, source, lineno, offset);
+        found.neglect(info.neglect);
+        return;
+    }
+
+    let note = format!("{}: {} {}", "note".white(), "this was", raw.grey());
+    let help = format!(
+        "{}: place a {} before the line to enable debug mode",
+        "help".white(),
+        "+".mint()
+    );
+
+    let more = match info.debug {
+        true => vec![note],
+        false => vec![note, help],
+    };
+    let lineno = joined.lineno();
+    let column = line.get(..offset).map_or(offset, |text| text.chars().count());
+    let found = diagnostics.processed_error(kind, message, filename, line, more, lineno, column);
+    found.neglect(info.neglect);
+}
+
+/// Records an error from deriving part of a line, given the byte offset where that part starts
+fn derive_error(
+    diagnostics: &mut Diagnostics,
+    err: &ast::Error,
+    filename: &str,
+    joined: &Joined,
+    info: &LineInfo,
+    offset: usize,
+) {
+    let help = format!(
+        "{}: place a {} before the line to enable debug mode",
+        "help".white(),
+        "+".mint()
+    );
+
+    let more = match info.debug {
+        true => vec![],
+        false => vec![help],
+    };
+    let offset = offset + err.span.0;
+    let (source, lineno, column) = joined.locate(offset);
+    let message = &err.message;
+    let found =
+        diagnostics.processed_error("Subcall", message, filename, source, more, lineno, column);
+    found.neglect(info.neglect);
+}
+
+fn main() {
+    let args: Vec<_> = std::env::args_os().collect();
+    if let [_, helper, log, line] = args.as_slice() {
+        if helper == trace::HELPER {
+            let line = line.to_string_lossy();
+            std::process::exit(trace::helper(Path::new(log), &line));
+        }
+    }
+
+    // clap would still read a subcommand after --, so it only sees the arguments before it
+    let (args, escaped) = match args.iter().position(|arg| arg == "--") {
+        Some(split) => (&args[..split], &args[split + 1..]),
+        None => (&args[..], &[][..]),
+    };
+    let mut opt = Opt::from_iter(args);
+    if opt.hayfile.is_none() && opt.goals.first().is_some_and(|goal| Path::new(goal).is_file()) {
+        opt.hayfile = Some(PathBuf::from(opt.goals.remove(0)));
+    }
+    opt.goals
+        .extend(escaped.iter().map(|goal| goal.to_string_lossy().to_string()));
+
+    let dir = PathBuf::from(".haymaker");
+
+    if let Some(Subcommand::Daemon(command)) = &opt.command {
+        let result = match command {
+            DaemonCommand::Start => daemon::start(&dir),
+            DaemonCommand::Stop => daemon::stop(&dir),
+            DaemonCommand::Serve => daemon::serve(&dir),
+        };
+        if let Err(err) = result {
+            println!("Daemon failed\n{}", err);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    for (variable, value) in &vars {
-        println!("{} {} {}", variable, "≡".pink(), add_derivation_highlights(value));
+    if let Some(Subcommand::Cache(command)) = &opt.command {
+        let cache = Cache::new(Cache::default_dir());
+
+        match command {
+            CacheCommand::Stats => {
+                let stats = cache.stats();
+                println!("{} {}", "cache".blue(), cache.dir().to_string_lossy());
+                println!("{} {}", stats.entries, "recipe".plural(stats.entries));
+                println!("{} {}", stats.blobs, "blob".plural(stats.blobs));
+                println!("{} {}", stats.bytes, "byte".plural(stats.bytes as usize));
+            }
+            CacheCommand::Gc { max_age, max_size } => {
+                let max_age = Duration::from_secs(*max_age * 24 * 60 * 60);
+                let removed = cache.gc(max_age, *max_size);
+                println!("removed {} {}", removed, "file".plural(removed));
+            }
+        }
+        return;
+    }
+
+    let hayfile = match opt.hayfile.clone() {
+        Some(hayfile) => hayfile,
+        None => {
+            let defaults = ["hayfile", "Hayfile", "makefile", "Makefile"];
+
+            match defaults.into_iter().find(|file| Path::new(file).exists()) {
+                Some(hayfile) => Path::new(hayfile).to_path_buf(),
+                None => {
+                    println!("No {} in current directory", "hayfile".red());
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    // goals like name=value set variables instead, overriding the hayfile's assignments
+    let (overrides, goals): (Vec<_>, Vec<_>) = opt
+        .goals
+        .iter()
+        .cloned()
+        .partition(|goal| regexes::OVERRIDE.is_match(goal));
+    let overrides: VarMap = overrides
+        .iter()
+        .filter_map(|goal| goal.split_once('='))
+        .map(|(name, value)| (name.to_string(), Var::new(value.to_string(), Flavor::Override)))
+        .collect();
+
+    // every problem in the hayfile is reported at once, and any of them stops the build
+    let load = |hayfile: &Path| {
+        let (hay, mut diagnostics) = load(hayfile, &overrides);
+        diagnostics.print();
+        (diagnostics.errors() == 0).then_some(hay)
+    };
+
+    // the daemon, if any, has probably loaded the hayfile already, though without overrides
+    let fetch = |hayfile: &Path| match opt.no_daemon || !overrides.is_empty() {
+        true => load(hayfile),
+        false => match daemon::request(&dir, hayfile) {
+            Some(Ok((hay, warnings))) => {
+                print!("{}", warnings);
+                Some(hay)
+            }
+            Some(Err(errors)) => {
+                print!("{}", errors);
+                None
+            }
+            None => load(hayfile),
+        },
+    };
+
+    let mut hay = match fetch(&hayfile) {
+        Some(hay) => hay,
+        None => std::process::exit(1),
+    };
+
+    for (variable, var) in &hay.vars {
+        let value = add_derivation_highlights(&var.value);
+        let variable = [variable.as_str()]
+            .into_iter()
+            .chain(var.params.iter().map(String::as_str));
+        let variable = variable.collect_vec().join(" ");
+        match var.flavor {
+            Flavor::Recursive => println!("{} {} {}", variable, "≡".pink(), value),
+            flavor => println!("{} {} {} {}", variable, "≡".pink(), value, flavor.grey()),
+        }
+    }
+    for (pool, depth) in &hay.pools {
+        println!("{} {} {} {}", "pool".pink(), pool, "≡".pink(), depth);
     }
     println!();
 
-    for recipe in &recipes {
+    for recipe in &hay.recipes {
         recipe.print();
         println!();
     }
 
-    let mut graph: StableGraph<Recipe, ()> = StableGraph::new();
-    //let mut nodes: BTreeMap::new();
+    let settings = Settings {
+        dir: dir.clone(),
+        hermetic: opt.hermetic,
+        audit: opt.audit,
+        cache: match opt.cache {
+            true => Some(open_cache(&opt)),
+            false => None,
+        },
+        jobs: opt.jobs.unwrap_or(1).max(1),
+        buffer: opt.buffer,
+    };
 
-    for recipe in recipes {
-        let _node = graph.add_node(recipe);
-    }
+    let mut state = State::load(&settings.dir);
+    signals::install(settings.jobs);
 
-    while graph.node_count() > 0 {
-        let ready: Vec<_> = graph.externals(Direction::Outgoing).collect();
+    let (mut graph, mut targets) = match plan(&hay, &goals, &state) {
+        Ok(planned) => planned,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let mut build = true;
+
+    loop {
+        // watch before building so that edits made during the build aren't missed
+        let mut watched = graph.sources(&targets);
+        watched.extend(hay.files.iter().map(|file| file.to_string_lossy().to_string()));
+
+        let mut watcher = match opt.watch {
+            true => match Watcher::new(&watched) {
+                Ok(watcher) => Some(watcher),
+                Err(err) => {
+                    println!("Could not watch for changes\n{}", err);
+                    std::process::exit(1);
+                }
+            },
+            false => None,
+        };
+
+        let mut deps = state.deps.clone();
+        if build {
+            let report = graph.run(&targets, &hay.vars, &mut state, &settings);
+            if let Err(err) = state.save() {
+                println!("Could not save build state\n{}", err);
+            }
+            report.print();
 
-        for node in ready {
-            let recipe = &graph[node];
-            recipe.execute(&vars);
-            graph.remove_node(node);
+            if let Some(path) = &opt.junit {
+                if let Err(err) = junit::write(path, &report) {
+                    println!("Could not write {}\n{}", path.to_string_lossy().red(), err);
+                }
+            }
+
+            if let Some(signal) = signals::interrupted() {
+                signals::exit(signal);
+            }
+
+            if watcher.is_none() {
+                match report.failed() > 0 || report.undeclared > 0 {
+                    true => std::process::exit(1),
+                    false => return,
+                }
+            }
         }
+        build = true;
+
+        let watcher = watcher.as_mut().unwrap();
+
+        loop {
+            // depfiles may have named new inputs, which the graph must know about to watch them
+            if state.deps != deps {
+                if let Ok(planned) = plan(&hay, &goals, &state) {
+                    (graph, targets) = planned;
+                    build = false;
+                    break;
+                }
+                deps = state.deps.clone();
+            }
+
+            println!("{}", "watching for changes".grey());
+
+            let changed = match watcher.wait() {
+                Ok(changed) => changed,
+                Err(err) => {
+                    if let Some(signal) = signals::interrupted() {
+                        signals::exit(signal);
+                    }
+                    println!("Could not watch for changes\n{}", err);
+                    std::process::exit(1);
+                }
+            };
+            println!("{} {}", "changed".mint(), changed.join(" "));
+
+            // a broken hayfile is reported, and the last good one kept until it's fixed
+            let files = hay.files.iter().map(|file| file.to_string_lossy());
+            if files.into_iter().any(|file| changed.iter().any(|x| x == &file)) {
+                let Some(reloaded) = fetch(&hayfile) else {
+                    continue;
+                };
+                match plan(&reloaded, &goals, &state) {
+                    Ok(planned) => {
+                        (graph, targets) = planned;
+                        hay = reloaded;
+                        break;
+                    }
+                    Err(message) => {
+                        println!("{}", message);
+                        continue;
+                    }
+                }
+            }
+
+            let affected = graph.affected(&changed);
+            let affected = affected.intersection(&targets).cloned().collect();
+
+            let report = graph.run(&affected, &hay.vars, &mut state, &settings);
+            if let Err(err) = state.save() {
+                println!("Could not save build state\n{}", err);
+            }
+            report.print();
+
+            if let Some(path) = &opt.junit {
+                if let Err(err) = junit::write(path, &report) {
+                    println!("Could not write {}\n{}", path.to_string_lossy().red(), err);
+                }
+            }
+
+            if let Some(signal) = signals::interrupted() {
+                signals::exit(signal);
+            }
+        }
+    }
+}
+
+/// The graph of a hayfile's recipes, and the ones needed for the named goals
+fn plan(
+    hay: &Hayfile,
+    goals: &[String],
+    state: &State,
+) -> Result<(Graph, HashSet<NodeIndex>), String> {
+    let graph = Graph::new(hay.recipes.clone(), hay.pools.clone(), state)?;
+    let targets = graph.goals(goals)?;
+    Ok((graph, targets))
+}
+
+#[test]
+fn test_attributes() {
+    use crate::progress::Log;
+
+    let (hay, diagnostics) = load(Path::new("tests/attributes.hay"), &VarMap::new());
+    assert!(diagnostics.is_empty());
+    let recipe = &hay.recipes[0];
+    assert_eq!(
+        recipe.attributes(),
+        vec![
+            ("depfile", String::from("@out.d")),
+            ("retry", String::from("2"))
+        ]
+    );
+
+    // lines that only look like attributes are still commands, and run
+    let settings = Settings {
+        dir: PathBuf::from(".haymaker"),
+        hermetic: false,
+        audit: false,
+        cache: None,
+        jobs: 1,
+        buffer: None,
+    };
+    let mut log = Log::new(true);
+    assert!(recipe.execute(&hay.vars, &settings, &mut log).is_ok());
+    assert!(log.buffer.unwrap().lines().any(|line| line == "out.d"));
+}
+
+#[test]
+fn test_recipe_vars() {
+    use crate::progress::Log;
+
+    let (hay, diagnostics) = load(Path::new("tests/vars.hay"), &VarMap::new());
+    assert!(diagnostics.is_empty());
+    let recipe = &hay.recipes[0];
+    let vars = recipe.vars.iter().map(|var| (var.name.as_str(), var.propagate));
+    assert_eq!(vars.collect_vec(), vec![("greeting", false), ("level", true)]);
+
+    // without var, lines that look like assignments are still commands, and run
+    assert_eq!(recipe.commands.len(), 1);
+
+    let settings = Settings {
+        dir: PathBuf::from(".haymaker"),
+        hermetic: false,
+        audit: false,
+        cache: None,
+        jobs: 1,
+        buffer: None,
+    };
+    let mut log = Log::new(true);
+    assert!(recipe.execute(&hay.vars, &settings, &mut log).is_ok());
+    assert!(log.buffer.unwrap().lines().any(|line| line == "= hi 2"));
+}
+
+#[test]
+fn test_define() {
+    use crate::progress::Log;
+
+    let (hay, diagnostics) = load(Path::new("tests/define.hay"), &VarMap::new());
+    assert!(diagnostics.is_empty());
+
+    // the if inside the define is kept, rather than its end closing the define early
+    let release = &hay.vars["release"];
+    assert_eq!(release.params, vec!["src"]);
+    assert!(release.value.starts_with("if @debug\n"));
+    assert!(hay.vars.contains_key("after"));
+
+    // and is decided each time the define is called
+    let settings = Settings {
+        dir: PathBuf::from(".haymaker"),
+        hermetic: false,
+        audit: false,
+        cache: None,
+        jobs: 1,
+        buffer: None,
+    };
+    for (recipe, built) in hay.recipes.iter().zip(["optimized a.c", "debug a.c"]) {
+        let mut log = Log::new(true);
+        assert!(recipe.execute(&hay.vars, &settings, &mut log).is_ok());
+        let output = log.buffer.unwrap();
+        let output = output.lines().filter(|line| !line.contains("echo")).collect_vec();
+        assert_eq!(output, vec![built, "strip a.c"]);
     }
 }
+
+#[test]
+fn test_include() {
+    let (hay, diagnostics) = load(Path::new("tests/include.hay"), &VarMap::new());
+    assert!(diagnostics.is_empty());
+    let targets = hay.recipes.iter().map(Recipe::target);
+    assert_eq!(targets.collect_vec(), vec!["rules", "more", "first", "second", "all"]);
+
+    // matches are read in sorted order, and missing files under -include are skipped
+    // without an error, though still watched so that creating them reloads the hayfile
+    assert_eq!(hay.vars["parts"].value, "first second");
+    let files = hay.files.iter().map(|file| file.to_string_lossy()).collect_vec();
+    assert!(files.contains(&"tests/include/parts/20-second.hay".into()));
+    assert!(files.contains(&"tests/include/missing.hay".into()));
+
+    let (hay, diagnostics) = load(Path::new("tests/cycle/a.hay"), &VarMap::new());
+    let texts = diagnostics.texts();
+    assert_eq!(texts.len(), 2);
+    assert!(texts[0].contains("include cycle between"));
+    assert!(texts[1].contains("stray shell code outside of a recipe"));
+
+    let targets = hay.recipes.iter().map(Recipe::target);
+    assert_eq!(targets.collect_vec(), vec!["a", "b"]);
+    assert_eq!(hay.recipes[0].commands.len(), 1);
+
+    let (_, diagnostics) = load(Path::new("tests/unreadable.hay"), &VarMap::new());
+    let texts = diagnostics.texts();
+    assert_eq!(texts.len(), 1);
+    assert!(texts[0].contains("could not read") && texts[0].contains("unreadable.hay:2:"));
+
+    let (_, diagnostics) = load(Path::new("tests/nonexistent.hay"), &VarMap::new());
+    assert_eq!(diagnostics.errors(), 1);
+}
@@ -2,11 +2,83 @@
 // Haymaker
 //
 
+use crate::console::Color;
+
+use lalrpop_util::lexer::Token;
+use lalrpop_util::ParseError;
+
 pub enum MakeLine {
     Rule(Rule),
 }
 
+#[derive(Clone)]
 pub struct Rule {
     pub outputs: Vec<String>,
     pub steps: Vec<Vec<String>>,
+    pub order: Vec<String>, // order-only, never makes the rule stale
+}
+
+/// Describes why a line isn't a rule, along with the byte offset of the problem
+pub fn describe_error(err: ParseError<usize, Token, &str>, text: &str) -> (String, usize) {
+    match err {
+        ParseError::InvalidToken { location } => {
+            let found = text[location..].chars().next().unwrap_or(' ');
+            let message = format!("{} can't be used in a rule", found.red());
+            (message, location)
+        }
+        ParseError::UnrecognizedEOF { location, expected } => {
+            let message = format!("the rule ends early, expected {}", friendly(&expected));
+            (message, location)
+        }
+        ParseError::UnrecognizedToken { token, expected } => {
+            let (start, Token(_, found), _) = token;
+            let message = format!("unexpected {}, expected {}", found.red(), friendly(&expected));
+            (message, start)
+        }
+        ParseError::ExtraToken { token } => {
+            let (start, Token(_, found), _) = token;
+            (format!("unexpected {}", found.red()), start)
+        }
+        ParseError::User { error } => (error.to_string(), 0),
+    }
+}
+
+/// Lists the tokens the parser expected, naming patterns by what they match
+fn friendly(expected: &[String]) -> String {
+    let tokens = expected.iter().map(|token| match token.starts_with("r#") {
+        true => String::from("a name"),
+        false => token.trim_matches('"').white(),
+    });
+    let mut tokens = tokens.collect::<Vec<_>>();
+
+    match tokens.pop() {
+        Some(last) if tokens.is_empty() => last,
+        Some(last) => format!("{} or {}", tokens.join(", "), last),
+        None => String::from("nothing"),
+    }
+}
+
+#[test]
+fn test_describe_error() {
+    use crate::console;
+    use crate::def::DefParser;
+
+    let describe = |text| {
+        let err = DefParser::new().parse(text).err().unwrap();
+        let (message, offset) = describe_error(err, text);
+        let plain = [console::RED, console::WHITE, console::CLEAR]
+            .iter()
+            .fold(message, |message, color| message.replace(color, ""));
+        (plain, offset)
+    };
+
+    let (message, offset) = describe("all deps");
+    assert_eq!(message, "the rule ends early, expected : or a name");
+    assert_eq!(offset, 8);
+
+    let (message, offset) = describe("all: : b");
+    assert_eq!(message, "unexpected :, expected || or a name");
+    assert_eq!(offset, 5);
+
+    assert_eq!(describe("all: a$").1, 6);
 }
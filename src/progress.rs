@@ -0,0 +1,267 @@
+//
+// Haymaker
+//
+
+use crate::console::Color;
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// the status line currently drawn at the bottom of the terminal, if any
+static STATUS: Mutex<String> = Mutex::new(String::new());
+
+/// How often to report progress when the output isn't a terminal
+const PERIOD: Duration = Duration::from_secs(5);
+
+/// Prints to stdout above the status line
+pub fn print(text: &str) {
+    write(text, false);
+}
+
+/// Prints to stderr above the status line
+pub fn eprint(text: &str) {
+    write(text, true);
+}
+
+fn write(text: &str, stderr: bool) {
+    let status = STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut stdout = std::io::stdout().lock();
+
+    if !status.is_empty() {
+        let _ = write!(stdout, "\r\x1b[K");
+    }
+    match stderr {
+        true => {
+            let _ = stdout.flush();
+            let _ = std::io::stderr().write_all(text.as_bytes());
+        }
+        false => drop(stdout.write_all(text.as_bytes())),
+    }
+    if !status.is_empty() {
+        let _ = write!(stdout, "{}", status);
+    }
+    let _ = stdout.flush();
+}
+
+/// Where a recipe's output goes: straight to the terminal, or into a block printed all at once
+pub struct Log {
+    pub buffer: Option<String>,
+}
+
+impl Log {
+    pub fn new(buffered: bool) -> Self {
+        Log {
+            buffer: buffered.then(String::new),
+        }
+    }
+
+    pub fn print(&mut self, text: &str) {
+        match &mut self.buffer {
+            Some(buffer) => buffer.push_str(text),
+            None => print(text),
+        }
+    }
+
+    /// Prints to stderr, unless buffering, where both streams are combined
+    pub fn eprint(&mut self, text: &str) {
+        match &mut self.buffer {
+            Some(buffer) => buffer.push_str(text),
+            None => eprint(text),
+        }
+    }
+}
+
+/// Prints buffered output as blocks under a header naming the target,
+/// holding them back when they must come out in a fixed order
+pub struct Blocks {
+    order: Option<Vec<String>>,
+    next: usize, // the position in the order of the next block to print
+    held: HashMap<String, String>,
+}
+
+impl Blocks {
+    pub fn new(order: Option<Vec<String>>) -> Self {
+        Blocks {
+            order,
+            next: 0,
+            held: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, target: String, text: String) {
+        let order = match &self.order {
+            Some(order) => order,
+            None => return block(&target, &text),
+        };
+        self.held.insert(target, text);
+
+        while let Some(target) = order.get(self.next) {
+            match self.held.remove(target) {
+                Some(text) => block(target, &text),
+                None => break,
+            }
+            self.next += 1;
+        }
+    }
+
+    /// Prints the blocks still held, such as when a failure stopped the build early
+    pub fn flush(&mut self) {
+        for target in self.order.iter().flatten() {
+            if let Some(text) = self.held.remove(target) {
+                block(target, &text);
+            }
+        }
+    }
+}
+
+fn block(target: &str, text: &str) {
+    if !text.is_empty() {
+        print(&format!("{} {}\n{}", "──".blue(), target.blue(), text));
+    }
+}
+
+/// Tracks a build's progress for the status line
+pub struct Progress {
+    total: usize,
+    done: usize,
+    remaining: Duration, // the estimated time of the recipes yet to start
+    average: Duration,   // the estimate for recipes that have never run
+    running: Vec<(String, Instant, Duration)>, // target, start, estimate
+    tty: bool,
+    jobs: usize,
+    reported: Instant, // when the last plain line was printed
+}
+
+impl Progress {
+    /// Starts tracking the given targets, each with its duration in previous runs if known
+    pub fn new(targets: &[(String, Option<Duration>)], jobs: usize) -> Self {
+        let known = targets.iter().filter_map(|(_, time)| *time).collect::<Vec<_>>();
+        let average = match known.len() {
+            0 => Duration::ZERO,
+            count => known.iter().sum::<Duration>() / count as u32,
+        };
+
+        Progress {
+            total: targets.len(),
+            done: 0,
+            remaining: targets.iter().map(|(_, time)| time.unwrap_or(average)).sum(),
+            average,
+            running: vec![],
+            tty: std::io::stdout().is_terminal(),
+            jobs,
+            reported: Instant::now(),
+        }
+    }
+
+    pub fn start(&mut self, target: String, estimate: Option<Duration>) {
+        let estimate = estimate.unwrap_or(self.average);
+        self.remaining = self.remaining.saturating_sub(estimate);
+        self.running.push((target, Instant::now(), estimate));
+        self.draw();
+    }
+
+    pub fn finish(&mut self, target: &str) {
+        self.running.retain(|(running, ..)| running != target);
+        self.done += 1;
+        self.draw();
+    }
+
+    /// Redraws the status line, such as when time passes without any recipe finishing
+    pub fn draw(&mut self) {
+        if self.total == 0 {
+            return;
+        }
+
+        let counter = format!("[{}/{}]", self.done, self.total);
+        let mut line = String::new();
+
+        if !self.running.is_empty() {
+            let targets = self.running.iter().map(|(target, ..)| target.as_str());
+            line += &format!(" {}", targets.collect::<Vec<_>>().join(", "));
+        }
+        if let Some(eta) = self.eta() {
+            line += &format!("  eta {}", format_duration(eta));
+        }
+
+        match self.tty {
+            true => {
+                let width = terminal_width().saturating_sub(counter.len() + 1);
+                let line: String = line.chars().take(width).collect();
+                let mut status = STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                *status = format!("{}{}", counter.blue(), line);
+
+                let mut stdout = std::io::stdout().lock();
+                let _ = write!(stdout, "\r\x1b[K{}", status);
+                let _ = stdout.flush();
+            }
+            false => {
+                if self.reported.elapsed() >= PERIOD {
+                    self.reported = Instant::now();
+                    println!("{}{}", counter.blue(), line);
+                }
+            }
+        }
+    }
+
+    /// Removes the status line, once the build is over
+    pub fn clear(&self) {
+        let mut status = STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !status.is_empty() {
+            print!("\r\x1b[K");
+            let _ = std::io::stdout().flush();
+            *status = String::new();
+        }
+    }
+
+    /// The time left, assuming every job slot stays busy
+    fn eta(&self) -> Option<Duration> {
+        let running = self
+            .running
+            .iter()
+            .map(|(_, start, estimate)| estimate.saturating_sub(start.elapsed()));
+        let left = self.remaining + running.sum::<Duration>();
+
+        match left.is_zero() {
+            true => None,
+            false => Some(left / self.jobs as u32),
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        _ => format!("{}m{:02}s", secs / 60, secs % 60),
+    }
+}
+
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 => size.ws_col as usize,
+        _ => 80,
+    }
+}
+
+#[test]
+fn test_progress() {
+    let targets = [
+        (String::from("a"), Some(Duration::from_secs(4))),
+        (String::from("b"), Some(Duration::from_secs(2))),
+        (String::from("c"), None),
+    ];
+    let mut progress = Progress::new(&targets, 2);
+    progress.tty = false;
+    assert_eq!(progress.eta(), Some(Duration::from_secs(9) / 2));
+
+    progress.start(String::from("a"), Some(Duration::from_secs(4)));
+    progress.finish("a");
+    progress.start(String::from("c"), None);
+    assert_eq!(progress.done, 1);
+    assert_eq!(progress.remaining, Duration::from_secs(2));
+
+    assert_eq!(format_duration(Duration::from_secs(75)), "1m15s");
+}
@@ -2,27 +2,102 @@
 // Haymaker
 //
 
+use crate::ast;
+use crate::cache::{self, Cache};
 use crate::console::Color;
-use crate::derive::{add_derivation_highlights, derive, VarMap};
+use crate::depfile::parse_depfile;
+use crate::derive::{add_derivation_highlights, assign, derive, Assignment, Var, VarMap};
 use crate::parsed::Rule;
+use crate::progress::Log;
+use crate::sandbox::Sandbox;
+use crate::signals::{self, Group};
+use crate::trace;
 
-use std::process::Command;
+use itertools::Itertools;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
 
+#[derive(Clone)]
 pub struct Recipe {
     pub rule: Rule,
     pub commands: Vec<ShellCommand>,
+    pub depfile: Option<String>, // derived after the commands run
+    pub implicit: Vec<String>,   // inputs discovered by a previous run's depfile
+    pub retry: Option<Retry>,
+    pub pool: Option<String>, // limits how many recipes like this run at once
+    pub vars: Vec<Assignment>, // the target's own variables, layered over the globals
+    pub inherited: Vec<Assignment>, // variables propagated from the targets needing this one
 }
 
+#[derive(Clone)]
 pub struct ShellCommand {
     pub line: String,
     pub debug: bool,
 }
 
+/// The names of the attributes a recipe may set, each written as .name = value
+pub const ATTRIBUTES: [&str; 3] = ["depfile", "retry", "pool"];
+
+/// How recipes are run, as chosen on the command line
+pub struct Settings {
+    pub dir: PathBuf,   // where haymaker keeps its state
+    pub hermetic: bool, // run each recipe in a sandbox of its declared inputs
+    pub audit: bool,    // trace the files each command reads
+    pub cache: Option<Cache>,
+    pub jobs: usize, // how many recipes may run at once
+    pub buffer: Option<Buffer>,
+}
+
+/// When buffering, the order in which each recipe's output is printed as a block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Buffer {
+    Completion, // as soon as the recipe finishes
+    Stable,     // in an order that doesn't depend on timing, so logs can be diffed
+}
+
+/// How often to re-run a failing recipe, waiting twice as long before each new attempt
+#[derive(Clone, Copy)]
+pub struct Retry {
+    pub count: u32,
+    pub backoff: Duration,
+}
+
+/// What happened when a recipe ran
+#[derive(Default)]
+pub struct Outcome {
+    pub reads: Vec<String>,        // files read by the commands, when auditing
+    pub cached: bool,              // whether the outputs were restored rather than built
+    pub attempts: u32,             // how many times the commands ran
+    pub deps: Option<Vec<String>>, // inputs discovered through the depfile
+}
+
+/// Why a recipe failed
+#[derive(Debug, Default)]
+pub struct Failure {
+    pub message: String,
+    pub command: Option<String>, // the derived command that failed, if one did
+    pub stderr: String,          // what the failed command printed to stderr
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dependency {
+    Normal, // rebuilding the input rebuilds the output
+    Order,  // the input need only exist beforehand
+}
+
 impl From<Rule> for Recipe {
     fn from(rule: Rule) -> Self {
         Recipe {
             rule,
             commands: vec![],
+            depfile: None,
+            implicit: vec![],
+            retry: None,
+            pool: None,
+            vars: vec![],
+            inherited: vec![],
         }
     }
 }
@@ -32,6 +107,36 @@ impl Recipe {
         self.commands.push(ShellCommand { line, debug });
     }
 
+    pub fn set_attribute(&mut self, name: &str, value: String) -> Result<(), String> {
+        match name {
+            "depfile" => self.depfile = Some(value),
+            "retry" => self.retry = Some(value.parse()?),
+            "pool" => self.pool = Some(value),
+            _ => return Err(format!("{} is not an attribute", name.red())),
+        }
+        Ok(())
+    }
+
+    /// The attributes as they would be written in a hayfile
+    pub fn attributes(&self) -> Vec<(&str, String)> {
+        let mut attributes = vec![];
+        if let Some(depfile) = &self.depfile {
+            attributes.push(("depfile", depfile.clone()));
+        }
+        if let Some(retry) = &self.retry {
+            attributes.push(("retry", retry.to_string()));
+        }
+        if let Some(pool) = &self.pool {
+            attributes.push(("pool", pool.clone()));
+        }
+        attributes
+    }
+
+    /// The name under which haymaker remembers this recipe between runs
+    pub fn target(&self) -> String {
+        self.rule.outputs.join(" ")
+    }
+
     pub fn print(&self) {
         for (index, output) in self.rule.outputs.iter().enumerate() {
             let spacer = match index {
@@ -53,7 +158,28 @@ impl Recipe {
                 print!("{} ", need);
             }
         }
-        println!("");
+        if !self.rule.order.is_empty() {
+            print!("{}", "|| ".blue());
+
+            for need in &self.rule.order {
+                print!("{} ", need);
+            }
+        }
+        println!();
+
+        for (name, value) in self.attributes() {
+            let line = add_derivation_highlights(&value);
+            println!("\t{} {} {}", format!(".{}", name).pink(), "=".pink(), line);
+        }
+        for var in &self.vars {
+            let keyword = match var.propagate {
+                true => "var propagate",
+                false => "var",
+            };
+            let line = add_derivation_highlights(&var.value);
+            let operator = var.flavor.operator();
+            println!("\t{} {} {} {}", keyword.pink(), var.name, operator.pink(), line);
+        }
 
         for command in &self.commands {
             let line = add_derivation_highlights(&command.line);
@@ -61,42 +187,409 @@ impl Recipe {
         }
     }
 
-    pub fn execute(&self, globals: &VarMap) {
+    /// Whether any output is missing or older than a normal input.
+    /// Order-only inputs are never considered, since they only constrain when the recipe runs.
+    pub fn outdated(&self) -> bool {
+        let mut oldest = None;
+
+        for output in &self.rule.outputs {
+            let modified = match modified(output) {
+                Some(modified) => modified,
+                None => return true,
+            };
+            oldest = match oldest {
+                Some(oldest) if oldest < modified => Some(oldest),
+                _ => Some(modified),
+            };
+        }
+
+        let oldest = match oldest {
+            Some(oldest) => oldest,
+            None => return true,
+        };
+
+        for input in self.rule.steps.iter().flatten().chain(&self.implicit) {
+            match modified(input) {
+                Some(modified) if modified <= oldest => continue,
+                _ => return true,
+            }
+        }
+        false
+    }
+
+    pub fn execute(
+        &self,
+        globals: &VarMap,
+        settings: &Settings,
+        log: &mut Log,
+    ) -> Result<Outcome, Failure> {
         let mut vars = globals.clone();
 
         let mut all = vec![];
         let mut out = vec![];
 
         for (index, input) in self.rule.steps.iter().flatten().enumerate() {
-            vars.insert(format!("{}", index + 1), input.clone());
+            vars.insert(format!("{}", index + 1), Var::from(input.clone()));
             all.push(input.clone());
         }
         for (index, output) in self.rule.outputs.iter().enumerate() {
-            vars.insert(format!("{}'", index + 1), output.clone());
+            vars.insert(format!("{}'", index + 1), Var::from(output.clone()));
             out.push(output.clone());
         }
 
-        vars.insert(String::from("all"), all.join(" "));
-        vars.insert(String::from("out"), out.join(" "));
+        vars.insert(String::from("all"), Var::from(all.join(" ")));
+        vars.insert(String::from("out"), Var::from(out.join(" ")));
 
-        for command in &self.commands {
-            let line = &command.line;
-            let debug = command.debug;
+        // the target's own variables win over those of the targets needing it
+        for var in self.inherited.iter().chain(&self.vars) {
+            let names = [var.name.clone()];
+            assign(&mut vars, &names, var.flavor, &var.value, false)?;
+        }
+
+        // the cache key holds every derived line, so with a cache they're all derived up front,
+        // and otherwise each is derived right before it runs, seeing what earlier ones made
+        let cache = match (&settings.cache, self.commands.is_empty()) {
+            (Some(cache), false) => {
+                let mut derived = vec![];
+                for command in &self.commands {
+                    derived.push(derive_command(command, &mut vars)?);
+                }
+                let depfile = self.derive_depfile(&mut vars)?;
+                let key = self.key(&derived.concat(), &depfile);
+                Some((cache, key, derived, depfile))
+            }
+            _ => None,
+        };
+        let derived = cache.as_ref().map(|(_, _, derived, _)| derived.as_slice());
+
+        let mut outcome = Outcome::default();
+
+        if let Some((cache, key, ..)) = &cache {
+            if let Some(entry) = cache.lookup(key) {
+                if entry.current() && cache.restore(&entry, &self.rule.outputs) {
+                    let deps = entry.deps.into_iter().map(|(dep, _)| dep).collect();
+                    outcome.deps = Some(deps);
+                    outcome.cached = true;
+                    return Ok(outcome);
+                }
+            }
+        }
+
+        let sandbox = match settings.hermetic {
+            true => {
+                let inputs = self.rule.steps.iter().flatten().chain(&self.implicit).collect_vec();
+                let outputs = &self.rule.outputs;
+                match Sandbox::new(&settings.dir, &inputs, &self.rule.order, outputs) {
+                    Ok(sandbox) => Some(sandbox),
+                    Err(err) => return Err(format!("could not create sandbox: {}", err).into()),
+                }
+            }
+            false => None,
+        };
+        let cwd = match &sandbox {
+            Some(sandbox) => sandbox.dir(),
+            None => Path::new("."),
+        };
+
+        let retry = self.retry.unwrap_or(Retry {
+            count: 0,
+            backoff: Duration::ZERO,
+        });
+        let mut backoff = retry.backoff;
 
-            let line = match derive(&line, &mut vars, debug) {
-                Ok(line) => line,
-                Err(err) => continue,
+        loop {
+            outcome.attempts += 1;
+
+            let run = self.run(derived, &mut vars.clone(), cwd, settings, &mut outcome, log);
+            let mut failure = match run {
+                Ok(()) => break,
+                Err(failure) => failure,
             };
+            if outcome.attempts > retry.count || signals::interrupted().is_some() {
+                if outcome.attempts > 1 {
+                    failure.message += &format!(" after {} attempts", outcome.attempts);
+                }
+                return Err(failure);
+            }
+
+            let message = &failure.message;
+            log.print(&format!("{}: {}, retrying\n", self.target().yellow(), message));
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
 
-            println!("{}", line.grey());
-            let command = Command::new("sh").arg("-c").arg(line).output();
+        if let Some(sandbox) = &sandbox {
+            if let Err(err) = sandbox.collect(&self.rule.outputs) {
+                return Err(format!("could not copy outputs out of the sandbox: {}", err).into());
+            }
+        }
+
+        let depfile = match &cache {
+            Some((.., depfile)) => depfile.clone(),
+            None => self.derive_depfile(&mut vars)?,
+        };
+        if let Some(depfile) = &depfile {
+            // a missing depfile means the compiler failed, so keep what we knew before
+            if let Ok(text) = std::fs::read_to_string(cwd.join(depfile)) {
+                let deps = parse_depfile(&text).into_iter();
+                let deps = deps.filter(|dep| !self.rule.outputs.contains(dep)).collect();
+                outcome.deps = Some(deps);
+            }
+        }
+
+        if let Some((cache, key, ..)) = &cache {
+            let deps = outcome.deps.as_ref().unwrap_or(&self.implicit);
+            if let Err(err) = cache.store(key, &self.rule.outputs, deps) {
+                let target = self.target();
+                log.print(&format!(
+                    "{}: could not cache {}: {}\n",
+                    "Cache".yellow(),
+                    target,
+                    err
+                ));
+            }
+        }
+        Ok(outcome)
+    }
+
+    fn derive_depfile(&self, vars: &mut VarMap) -> Result<Option<String>, Failure> {
+        match &self.depfile {
+            Some(depfile) => Ok(Some(derive(depfile, vars, false)?.trim().to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Runs each command in turn, stopping at the first failure.
+    /// Commands not derived already are derived just before they run.
+    fn run(
+        &self,
+        derived: Option<&[Vec<String>]>,
+        vars: &mut VarMap,
+        cwd: &Path,
+        settings: &Settings,
+        outcome: &mut Outcome,
+        log: &mut Log,
+    ) -> Result<(), Failure> {
+        let before = self.rule.outputs.iter().map(|output| modified(output)).collect_vec();
+
+        for (index, command) in self.commands.iter().enumerate() {
+            let lines = match derived {
+                Some(derived) => derived[index].clone(),
+                None => derive_command(command, vars)?,
+            };
+            self.run_lines(&lines, cwd, settings, outcome, log, &before)?;
+        }
+        Ok(())
+    }
+
+    fn run_lines(
+        &self,
+        lines: &[String],
+        cwd: &Path,
+        settings: &Settings,
+        outcome: &mut Outcome,
+        log: &mut Log,
+        before: &[Option<SystemTime>],
+    ) -> Result<(), Failure> {
+        for line in lines {
+            log.print(&format!("{}\n", line.grey()));
+
+            let (mut command, traced) = match settings.audit {
+                true => match trace::command(line, &settings.dir) {
+                    Ok((command, traced)) => (command, Some(traced)),
+                    Err(err) => return Err(format!("could not trace command: {}", err).into()),
+                },
+                false => {
+                    let mut command = Command::new("sh");
+                    command.arg("-c").arg(line);
+                    (command, None)
+                }
+            };
+            let command = command
+                .current_dir(cwd)
+                .process_group(0)
+                .stdin(Stdio::null()) // a background group reading the terminal would be stopped
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .and_then(|mut child| match Group::new(child.id()) {
+                    Ok(_group) => child.wait_with_output(),
+                    Err(err) => {
+                        let _ = child.wait();
+                        Err(err)
+                    }
+                });
+
+            if let Some(traced) = traced {
+                for read in trace::reads(&traced, cwd) {
+                    if !outcome.reads.contains(&read) {
+                        outcome.reads.push(read);
+                    }
+                }
+            }
 
             let output = match command {
                 Ok(output) => output,
-                Err(err) => panic!("{}", err),
+                Err(err) => return Err(format!("could not run sh: {}", err).into()),
             };
 
-            print!("{}", String::from_utf8_lossy(&output.stdout));
+            log.print(&String::from_utf8_lossy(&output.stdout));
+            log.eprint(&String::from_utf8_lossy(&output.stderr));
+
+            if signals::interrupted().is_some() {
+                self.remove_outputs(before);
+                return Err(String::from("interrupted").into());
+            }
+
+            if !output.status.success() {
+                return Err(Failure {
+                    message: match output.status.code() {
+                        Some(code) => format!("command exited with status {}", code),
+                        None => String::from("command was killed by a signal"),
+                    },
+                    command: Some(line.clone()),
+                    stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the outputs written since the recipe started, which may be incomplete
+    fn remove_outputs(&self, before: &[Option<SystemTime>]) {
+        for (output, before) in self.rule.outputs.iter().zip(before) {
+            let after = modified(output);
+            if after.is_some() && after != *before {
+                drop(std::fs::remove_file(output));
+            }
+        }
+    }
+
+    /// Identifies a recipe's work by its declared inputs' contents and its derived commands,
+    /// which capture every variable they use. Paths are relative, so checkouts share keys.
+    fn key(&self, lines: &[String], depfile: &Option<String>) -> String {
+        let mut text = format!("haymaker {}\n", env!("CARGO_PKG_VERSION"));
+
+        for output in &self.rule.outputs {
+            text += &format!("out {}\n", output);
+        }
+        for input in self.rule.steps.iter().flatten() {
+            text += &format!("in {} {}\n", cache::hash_file(input), input);
+        }
+        for line in lines {
+            text += &format!("run {}\n", line);
         }
+        if let Some(depfile) = depfile {
+            text += &format!("depfile {}\n", depfile);
+        }
+        cache::hash_bytes(text.as_bytes())
     }
 }
+
+/// Derives a command, which for macros may hold several, one on each line
+fn derive_command(command: &ShellCommand, vars: &mut VarMap) -> Result<Vec<String>, Failure> {
+    let line = derive(&command.line, vars, command.debug)?;
+    Ok(match line.contains('\n') {
+        true => line
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        false => vec![line],
+    })
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl std::str::FromStr for Buffer {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "completion" => Ok(Buffer::Completion),
+            "stable" => Ok(Buffer::Stable),
+            _ => Err(format!("{} is not completion or stable", text)),
+        }
+    }
+}
+
+impl From<ast::Error> for Failure {
+    fn from(error: ast::Error) -> Self {
+        Failure::from(error.message)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure {
+            message,
+            ..Failure::default()
+        }
+    }
+}
+
+impl std::str::FromStr for Retry {
+    type Err = String;
+
+    /// Parses a count and an optional backoff, like `3` or `3 500ms`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut words = text.split_whitespace();
+
+        let count = match words.next().map(|count| count.parse()) {
+            Some(Ok(count)) => count,
+            _ => return Err(format!("{} is not a number of retries", text)),
+        };
+        let backoff = match words.next() {
+            Some(backoff) => parse_duration(backoff)?,
+            None => Duration::ZERO,
+        };
+        if words.next().is_some() {
+            return Err(String::from("expected a count and a backoff"));
+        }
+        Ok(Retry { count, backoff })
+    }
+}
+
+impl std::fmt::Display for Retry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.backoff.is_zero() {
+            true => write!(f, "{}", self.count),
+            false => write!(f, "{} {}ms", self.count, self.backoff.as_millis()),
+        }
+    }
+}
+
+/// Parses durations like `250ms`, `2s` or `1m`, where a bare number counts seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+
+    let number: u64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => return Err(format!("{} is not a duration", text)),
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(format!("{} is not a duration", text)),
+    }
+}
+
+#[test]
+fn test_retry() {
+    let retry: Retry = "3".parse().unwrap();
+    assert_eq!((retry.count, retry.backoff), (3, Duration::ZERO));
+    assert_eq!(retry.to_string(), "3");
+
+    let retry: Retry = "2 1s".parse().unwrap();
+    assert_eq!((retry.count, retry.backoff), (2, Duration::from_secs(1)));
+    assert_eq!(retry.to_string().parse::<Retry>().unwrap().backoff, retry.backoff);
+
+    assert!("many".parse::<Retry>().is_err());
+    assert!("2 soon".parse::<Retry>().is_err());
+    assert!("2 1s 2s".parse::<Retry>().is_err());
+}
@@ -9,5 +9,23 @@ lazy_static! {
     pub static ref VAR: Regex = Regex::new(r"[\p{Alphabetic}\pN_-]+").unwrap();
     pub static ref VAR_CHAR: Regex = Regex::new(r"^[\p{Alphabetic}\pN_-]$").unwrap();
     pub static ref VAR_AT: Regex = Regex::new(r"^[\p{Alphabetic}\pN_-]+").unwrap();
+    pub static ref ATTRIBUTE: Regex =
+        Regex::new(r"^\.([\p{Alphabetic}\pN_-]+)\s*=\s*(.*)$").unwrap();
+    pub static ref POOL: Regex =
+        Regex::new(r"^pool\s+([\p{Alphabetic}\pN_-]+)\s*=\s*(.*)$").unwrap();
+    pub static ref CONDITION: Regex = Regex::new(r"^(else\s+)?if\s+([^=:+?\s].*)$").unwrap();
+    pub static ref ASSIGNMENT: Regex = Regex::new(r"^([^=]*?)\s*([+?:]=)(.*)$").unwrap();
+    pub static ref TARGET_ASSIGNMENT: Regex = Regex::new(
+        r"^(?P<targets>[^:=]+):\s*(?P<propagate>propagate\s+)?(?P<name>[\p{Alphabetic}\pN_-]+)\s*(?P<operator>[+?:]?=)(?P<value>.*)$"
+    )
+    .unwrap();
+    pub static ref RECIPE_ASSIGNMENT: Regex = Regex::new(
+        r"^var\s+(?P<propagate>propagate\s+)?(?P<name>[\p{Alphabetic}\pN_-]+)\s*(?P<operator>[+?:]?=)(?P<value>.*)$"
+    )
+    .unwrap();
+    pub static ref OVERRIDE: Regex = Regex::new(r"^[\p{Alphabetic}\pN_-]+=").unwrap();
+    pub static ref DEFINE: Regex =
+        Regex::new(r"^define\s+([\p{Alphabetic}\pN_-]+)((?:\s+[\p{Alphabetic}\pN_-]+)*)\s*$").unwrap();
+    pub static ref COLOR: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
     pub static ref VAR_AT_WITH_SIGN: Regex = Regex::new(r"^@[\p{Alphabetic}\pN_-]+").unwrap();
 }
@@ -0,0 +1,221 @@
+//
+// Haymaker
+//
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A plain HTTP content store laid out like bazel-remote, with action entries under `/ac/`
+/// and contents under `/cas/`. Only `http://` urls are supported.
+pub struct Remote {
+    host: String, // including the port
+    prefix: String,
+    pub read_only: bool,
+}
+
+impl Remote {
+    pub fn new(url: &str, read_only: bool) -> Result<Self, String> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => return Err(format!("{} is not an http:// url", url)),
+        };
+
+        let (host, prefix) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let host = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host),
+        };
+
+        Ok(Remote {
+            host,
+            prefix: prefix.to_owned(),
+            read_only,
+        })
+    }
+
+    /// Fetches `/<kind>/<hash>`, returning None when the store doesn't have it
+    pub fn get(&self, kind: &str, hash: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let (status, body) = self.request("GET", kind, hash, &[])?;
+        match status {
+            200 => Ok(Some(body)),
+            404 => Ok(None),
+            _ => Err(error(format!("GET {}/{} returned {}", kind, hash, status))),
+        }
+    }
+
+    pub fn put(&self, kind: &str, hash: &str, bytes: &[u8]) -> std::io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let (status, _) = self.request("PUT", kind, hash, bytes)?;
+        match status {
+            200..=299 => Ok(()),
+            _ => Err(error(format!("PUT {}/{} returned {}", kind, hash, status))),
+        }
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        kind: &str,
+        hash: &str,
+        body: &[u8],
+    ) -> std::io::Result<(u16, Vec<u8>)> {
+        let addr = match self.host.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(error(format!("could not resolve {}", self.host))),
+        };
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+        let path = format!("{}/{}/{}", self.prefix, kind, hash);
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            path,
+            self.host,
+            body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        parse_response(&response)
+    }
+}
+
+fn parse_response(response: &[u8]) -> std::io::Result<(u16, Vec<u8>)> {
+    let split = match response.windows(4).position(|x| x == b"\r\n\r\n") {
+        Some(split) => split,
+        None => return Err(error("malformed response".into())),
+    };
+    let head = String::from_utf8_lossy(&response[..split]);
+    let mut body = response[split + 4..].to_vec();
+
+    let mut lines = head.lines();
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    let status = match status.and_then(|status| status.parse().ok()) {
+        Some(status) => status,
+        None => return Err(error("malformed status line".into())),
+    };
+
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "content-length" => {
+                let len = value.parse().unwrap_or(body.len());
+                body.truncate(len);
+            }
+            "transfer-encoding" if value.eq_ignore_ascii_case("chunked") => {
+                body = unchunk(&body)?;
+            }
+            _ => {}
+        }
+    }
+    Ok((status, body))
+}
+
+fn unchunk(mut data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let end = match data.windows(2).position(|x| x == b"\r\n") {
+            Some(end) => end,
+            None => return Err(error("malformed chunk".into())),
+        };
+        let size = String::from_utf8_lossy(&data[..end]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(error("malformed chunk size".into())),
+        };
+        if size == 0 || data.len() < end + 2 + size {
+            return Ok(body);
+        }
+        body.extend(&data[end + 2..end + 2 + size]);
+        data = &data[(end + 4 + size).min(data.len())..];
+    }
+}
+
+fn error(message: String) -> std::io::Error {
+    std::io::Error::other(message)
+}
+
+/// A stand-in for a real content store that keeps everything in memory
+#[cfg(test)]
+pub fn serve() -> String {
+    use std::collections::HashMap;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        let mut store: HashMap<String, Vec<u8>> = HashMap::new();
+
+        for stream in listener.incoming().flatten() {
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let parts: Vec<_> = line.split(' ').collect();
+            let (method, path) = (parts[0].to_owned(), parts[1].to_owned());
+
+            let mut len = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                    len = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            let response = match (method.as_str(), store.get(&path)) {
+                ("PUT", _) => {
+                    store.insert(path, body);
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()
+                }
+                ("GET", Some(found)) => {
+                    let head =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", found.len());
+                    [head.as_bytes(), found].concat()
+                }
+                _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            (&stream).write_all(&response).unwrap();
+        }
+    });
+    format!("http://{}/cache", addr)
+}
+
+#[test]
+fn test_remote() {
+    let url = serve();
+    let remote = Remote::new(&url, false).unwrap();
+
+    assert_eq!(remote.get("cas", "abc").unwrap(), None);
+    remote.put("cas", "abc", b"contents").unwrap();
+    assert_eq!(remote.get("cas", "abc").unwrap(), Some(b"contents".to_vec()));
+
+    let untrusted = Remote::new(&url, true).unwrap();
+    untrusted.put("cas", "def", b"sneaky").unwrap();
+    assert_eq!(remote.get("cas", "def").unwrap(), None);
+    assert_eq!(untrusted.get("cas", "abc").unwrap(), Some(b"contents".to_vec()));
+
+    let chunked =
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n2\r\nef\r\n0\r\n\r\n";
+    assert_eq!(parse_response(chunked).unwrap(), (200, b"abcdef".to_vec()));
+    assert!(Remote::new("https://example.com", false).is_err());
+}
@@ -0,0 +1,158 @@
+//
+// Haymaker
+//
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A scratch directory holding only a recipe's declared inputs. Commands run inside it, so
+/// reading anything undeclared by a relative path fails, and only the declared outputs are
+/// copied back out. Nothing stops commands using absolute paths or ones leaving the directory,
+/// though declared paths that would put files outside it are refused.
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(
+        dir: &Path,
+        inputs: &[&String],
+        order: &[String],
+        outputs: &[String],
+    ) -> std::io::Result<Self> {
+        let name = format!("{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+        let root = dir.join("sandbox").join(name);
+
+        if root.exists() {
+            std::fs::remove_dir_all(&root)?;
+        }
+        std::fs::create_dir_all(&root)?;
+        let sandbox = Sandbox { root };
+
+        for input in inputs {
+            let path = Path::new(input);
+            if path.is_absolute() || !path.exists() {
+                continue; // system files stay visible, and phony inputs have nothing to link
+            }
+            let link = sandbox.prepare(path)?;
+            if !link.exists() {
+                std::os::unix::fs::symlink(path.canonicalize()?, link)?;
+            }
+        }
+
+        // order-only inputs are usually output directories, which must be created here
+        // rather than linked, lest the outputs be written straight into the real tree
+        for input in order {
+            let path = Path::new(input);
+            if path.is_dir() && !path.is_absolute() {
+                std::fs::create_dir_all(sandbox.inside(path)?)?;
+            }
+        }
+
+        for output in outputs {
+            sandbox.prepare(Path::new(output))?;
+        }
+        Ok(sandbox)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where a path is inside the sandbox, refusing absolute ones and those leaving it with ..
+    fn inside(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let mut inside = self.root.clone();
+        let mut depth = 0_usize;
+
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => {
+                    inside.push(part);
+                    depth += 1;
+                }
+                Component::CurDir => {}
+                Component::ParentDir if depth > 0 => {
+                    inside.pop();
+                    depth -= 1;
+                }
+                _ => {
+                    let message = format!("{} leaves the sandbox", path.display());
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+                }
+            }
+        }
+        Ok(inside)
+    }
+
+    /// Creates the parent directories of a path inside the sandbox
+    fn prepare(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let inside = self.inside(path)?;
+        if let Some(parent) = inside.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(inside)
+    }
+
+    /// Copies the declared outputs back into the real tree. Phony outputs never exist.
+    pub fn collect(&self, outputs: &[String]) -> std::io::Result<()> {
+        for output in outputs {
+            let inside = self.inside(Path::new(output))?;
+
+            if inside.is_symlink() || !inside.exists() {
+                continue;
+            }
+            if let Some(parent) = Path::new(output).parent() {
+                if parent != Path::new("") {
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+            copy(&inside, Path::new(output))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+fn copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        std::fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sandbox() {
+    let dir = std::env::temp_dir().join(format!("haymaker-sandbox-{}", std::process::id()));
+    let outputs = |outputs: &[&str]| outputs.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    let sandbox = Sandbox::new(&dir, &[], &[], &outputs(&["out/a.o", "out/../b.o"])).unwrap();
+    assert!(sandbox.dir().join("out").is_dir());
+    assert_eq!(
+        sandbox.inside(Path::new("out/../b.o")).unwrap(),
+        sandbox.dir().join("b.o")
+    );
+
+    // declared paths may not put files outside the sandbox
+    for escaping in ["../x", "out/../../x", "/tmp/x"] {
+        assert!(Sandbox::new(&dir, &[], &[], &outputs(&[escaping])).is_err());
+    }
+    let escaping = String::from("..");
+    assert!(Sandbox::new(&dir, &[], &[escaping], &[]).is_err());
+
+    drop(sandbox);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,124 @@
+//
+// Haymaker
+//
+
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// commands run in their own process groups so that a terminal's ctrl-c reaches haymaker alone,
+// and haymaker decides what the commands see. The handler may only touch atomics.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+static GROUPS: AtomicPtr<AtomicI32> = AtomicPtr::new(std::ptr::null_mut()); // a slot per job
+static SLOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How long interrupted commands have to exit before they're killed
+const GRACE: Duration = Duration::from_secs(3);
+
+/// Forwards SIGINT and SIGTERM to every running command, of which there are at most jobs
+pub fn install(jobs: usize) {
+    let slots: Box<[AtomicI32]> = (0..jobs).map(|_| AtomicI32::new(0)).collect();
+    SLOTS.store(slots.len(), Ordering::SeqCst);
+    GROUPS.store(Box::leak(slots).as_mut_ptr(), Ordering::SeqCst);
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = forward as extern "C" fn(i32) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            // no SA_RESTART, so blocking reads such as the watcher's return early
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+
+    std::thread::spawn(|| {
+        while interrupted().is_none() {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let since = Instant::now();
+
+        while since.elapsed() < GRACE {
+            if groups().iter().all(|group| group.load(Ordering::SeqCst) == 0) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        signal_all(libc::SIGKILL);
+    });
+}
+
+extern "C" fn forward(signal: i32) {
+    SIGNAL.store(signal, Ordering::SeqCst);
+    signal_all(signal);
+}
+
+/// The slots holding running commands' process groups, of which there are none until installed
+fn groups() -> &'static [AtomicI32] {
+    let groups = GROUPS.load(Ordering::SeqCst);
+    match groups.is_null() {
+        true => &[],
+        false => unsafe { std::slice::from_raw_parts(groups, SLOTS.load(Ordering::SeqCst)) },
+    }
+}
+
+fn signal_all(signal: i32) {
+    for group in groups() {
+        let pgid = group.load(Ordering::SeqCst);
+        if pgid != 0 {
+            unsafe { libc::killpg(pgid, signal) };
+        }
+    }
+}
+
+/// The signal that interrupted the build, if any
+pub fn interrupted() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// A running command's process group, which receives forwarded signals until dropped
+pub struct Group {
+    slot: Option<usize>,
+}
+
+impl Group {
+    /// Registers the group, or kills it when there's no free slot, since ctrl-c couldn't reach it
+    pub fn new(pgid: u32) -> std::io::Result<Self> {
+        let pgid = pgid as i32;
+        let slot = groups().iter().position(|group| {
+            let free = group.compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst);
+            free.is_ok()
+        });
+
+        let installed = !GROUPS.load(Ordering::SeqCst).is_null();
+        if installed && slot.is_none() {
+            unsafe { libc::killpg(pgid, libc::SIGKILL) };
+            let message = "more commands are running than there are jobs to forward signals to";
+            return Err(std::io::Error::other(message));
+        }
+
+        // a signal may have arrived before the group was registered
+        if let Some(signal) = interrupted() {
+            unsafe { libc::killpg(pgid, signal) };
+        }
+        Ok(Group { slot })
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            groups()[slot].store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Exits the way the signal would have, so the calling shell sees the interrupt
+pub fn exit(signal: i32) -> ! {
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    std::process::exit(128 + signal);
+}
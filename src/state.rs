@@ -0,0 +1,68 @@
+//
+// Haymaker
+//
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What haymaker remembers between runs, kept in a tab-separated file under `.haymaker`
+#[derive(Default)]
+pub struct State {
+    path: PathBuf,
+    pub deps: BTreeMap<String, Vec<String>>, // target -> implicit inputs discovered from depfiles
+    pub times: BTreeMap<String, Duration>,   // target -> how long it last took to build
+}
+
+impl State {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join("state");
+        let mut state = State {
+            path,
+            ..State::default()
+        };
+
+        let text = match std::fs::read_to_string(&state.path) {
+            Ok(text) => text,
+            Err(_) => return state,
+        };
+
+        for line in text.lines() {
+            let mut fields = line.split('\t');
+
+            match (fields.next(), fields.next()) {
+                (Some("deps"), Some(target)) => {
+                    let deps = fields.map(String::from).collect();
+                    state.deps.insert(target.to_owned(), deps);
+                }
+                (Some("time"), Some(target)) => {
+                    if let Some(Ok(millis)) = fields.next().map(str::parse) {
+                        state.times.insert(target.to_owned(), Duration::from_millis(millis));
+                    }
+                }
+                _ => continue, // unknown entries come from other versions
+            }
+        }
+        state
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut text = String::new();
+
+        for (target, deps) in &self.deps {
+            text += &format!("deps\t{}", target);
+            for dep in deps {
+                text += &format!("\t{}", dep);
+            }
+            text += "\n";
+        }
+        for (target, time) in &self.times {
+            text += &format!("time\t{}\t{}\n", target, time.as_millis());
+        }
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, text)
+    }
+}
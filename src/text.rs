@@ -3,7 +3,6 @@
 //
 
 pub trait Text {
-    fn split_when_balanced(&self, on: char, quote: char) -> Vec<&str>;
     fn split_when_balanced_with_offsets(&self, on: char, quote: char) -> Vec<(usize, &str)>;
     fn or_quotes(&self) -> String;
     fn plural(&self, num: usize) -> String;
@@ -33,14 +32,9 @@ where
         parts.into_iter().filter(|(_, s)| !s.is_empty()).collect()
     }
 
-    fn split_when_balanced(&self, on: char, quote: char) -> Vec<&str> {
-        let splits = self.split_when_balanced_with_offsets(on, quote);
-        splits.into_iter().map(|(_, s)| s).collect()
-    }
-
     fn or_quotes(&self) -> String {
         let text = self.as_ref();
-        String::from(match text == "" {
+        String::from(match text.is_empty() {
             true => "''",
             false => text,
         })
@@ -62,6 +56,6 @@ where
             }
             count += 1;
         }
-        return (&text[text.len() - 1..], count);
+        (&text[text.len() - 1..], count)
     }
 }
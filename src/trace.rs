@@ -0,0 +1,310 @@
+//
+// Haymaker
+//
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The argument that turns haymaker into a tracer for a single command
+pub const HELPER: &str = "__trace";
+
+/// Builds a command that runs `line` under haymaker's tracer, along with the log its reads go to.
+/// Tracing happens in a separate process since waiting on the tracees would otherwise reap
+/// unrelated children.
+pub fn command(line: &str, dir: &Path) -> std::io::Result<(Command, PathBuf)> {
+    // the command may run in a sandbox, so the log's path can't be relative
+    let dir = std::path::absolute(dir)?;
+    let name = format!("{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+    let log = dir.join("trace").join(name);
+    std::fs::create_dir_all(dir.join("trace"))?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg(HELPER).arg(&log).arg(line);
+    Ok((command, log))
+}
+
+/// The files a traced command read, relative to the directory it ran in.
+/// Files outside that directory, such as system headers, are left out.
+pub fn reads(log: &Path, cwd: &Path) -> Vec<String> {
+    let text = std::fs::read_to_string(log).unwrap_or_default();
+    let _ = std::fs::remove_file(log);
+
+    let cwd = match cwd.canonicalize() {
+        Ok(cwd) => cwd,
+        Err(_) => return vec![],
+    };
+
+    let mut reads = vec![];
+    for line in text.lines() {
+        let path = match line.strip_prefix("r\t") {
+            Some(path) => normalize(Path::new(path)),
+            None => continue,
+        };
+        if let Ok(path) = path.strip_prefix(&cwd) {
+            let path = path.to_string_lossy().to_string();
+            if !reads.contains(&path) {
+                reads.push(path);
+            }
+        }
+    }
+    reads
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+/// Runs `sh -c line`, logging every file opened by it or its descendants, and returns the
+/// exit status of the shell. Only called in the helper process.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub fn helper(log: &Path, line: &str) -> i32 {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(line);
+
+    unsafe {
+        command.pre_exec(|| match libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+
+    let child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("could not trace sh: {}", err);
+            return 127;
+        }
+    };
+    let shell = child.id() as libc::pid_t;
+
+    let mut opened = vec![];
+    let code = unsafe { follow(shell, &mut opened) };
+
+    let mut text = String::new();
+    for (path, write) in opened {
+        let mode = match write {
+            true => "w",
+            false => "r",
+        };
+        text += &format!("{}\t{}\n", mode, path.to_string_lossy());
+    }
+    if let Err(err) = std::fs::write(log, text) {
+        eprintln!("could not write trace log: {}", err);
+    }
+    code
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+pub fn helper(_log: &Path, _line: &str) -> i32 {
+    eprintln!("tracing file access is only supported on x86_64 linux");
+    1
+}
+
+/// Traces the shell and every process it spawns until the shell exits
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn follow(shell: libc::pid_t, opened: &mut Vec<(PathBuf, bool)>) -> i32 {
+    let mut status = 0;
+    if libc::waitpid(shell, &mut status, 0) == -1 || !libc::WIFSTOPPED(status) {
+        return 127;
+    }
+
+    let options = libc::PTRACE_O_TRACESYSGOOD
+        | libc::PTRACE_O_TRACEFORK
+        | libc::PTRACE_O_TRACEVFORK
+        | libc::PTRACE_O_TRACECLONE
+        | libc::PTRACE_O_TRACEEXEC
+        | libc::PTRACE_O_EXITKILL;
+    libc::ptrace(libc::PTRACE_SETOPTIONS, shell, 0, options);
+    libc::ptrace(libc::PTRACE_SYSCALL, shell, 0, 0);
+
+    let mut code = 127;
+    let mut executing = HashMap::new(); // the programs each process is switching to
+
+    loop {
+        let pid = libc::waitpid(-1, &mut status, libc::__WALL);
+        if pid == -1 {
+            break; // every tracee is gone
+        }
+
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            if pid == shell {
+                code = match libc::WIFEXITED(status) {
+                    true => libc::WEXITSTATUS(status),
+                    false => 128 + libc::WTERMSIG(status),
+                };
+            }
+            continue;
+        }
+        if !libc::WIFSTOPPED(status) {
+            continue;
+        }
+
+        let signal = match libc::WSTOPSIG(status) {
+            sig if sig == libc::SIGTRAP | 0x80 => {
+                syscall(pid, opened, &mut executing);
+                0
+            }
+            libc::SIGTRAP | libc::SIGSTOP => 0, // ptrace events and new children
+            sig => sig,
+        };
+        libc::ptrace(libc::PTRACE_SYSCALL, pid, 0, signal);
+    }
+    code
+}
+
+/// Records a successful open, if this syscall stop is one. Arguments to opens are read on exit,
+/// since x86_64 leaves them in place and the return value tells us whether the file existed.
+/// A successful execve replaces the memory holding its path, so that's read on entry instead.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn syscall(
+    pid: libc::pid_t,
+    opened: &mut Vec<(PathBuf, bool)>,
+    executing: &mut HashMap<libc::pid_t, PathBuf>,
+) {
+    let mut regs: libc::user_regs_struct = std::mem::zeroed();
+    libc::ptrace(libc::PTRACE_GETREGS, pid, 0, &mut regs);
+
+    let result = regs.rax as i64;
+    let entry = result == -(libc::ENOSYS as i64);
+
+    if regs.orig_rax as i64 == libc::SYS_execve {
+        match entry {
+            true => {
+                if let Some(path) = resolve(pid, libc::AT_FDCWD, regs.rdi) {
+                    executing.insert(pid, path);
+                }
+            }
+            false => {
+                let path = executing.remove(&pid);
+                if let Some(path) = path.filter(|_| result == 0) {
+                    record(opened, path, false);
+                }
+            }
+        }
+        return;
+    }
+    if entry || result < 0 {
+        return; // wait for the result, and skip failures
+    }
+
+    let (dirfd, path, flags) = match regs.orig_rax as i64 {
+        libc::SYS_open => (libc::AT_FDCWD, regs.rdi, regs.rsi),
+        libc::SYS_creat => (libc::AT_FDCWD, regs.rdi, libc::O_WRONLY as u64),
+        libc::SYS_openat => (regs.rdi as i32, regs.rsi, regs.rdx),
+        _ => return,
+    };
+
+    if let Some(path) = resolve(pid, dirfd, path) {
+        let write = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        record(opened, path, write);
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn record(opened: &mut Vec<(PathBuf, bool)>, path: PathBuf, write: bool) {
+    if !opened.contains(&(path.clone(), write)) {
+        opened.push((path, write));
+    }
+}
+
+/// Reads a path argument from a tracee, making it absolute from the directory it's relative to
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn resolve(pid: libc::pid_t, dirfd: i32, addr: u64) -> Option<PathBuf> {
+    let path = PathBuf::from(read_string(pid, addr)?);
+    if path.is_absolute() {
+        return Some(path);
+    }
+
+    let base = match dirfd {
+        libc::AT_FDCWD => format!("/proc/{}/cwd", pid),
+        fd => format!("/proc/{}/fd/{}", pid, fd),
+    };
+    std::fs::read_link(base).ok().map(|base| base.join(path))
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn read_string(pid: libc::pid_t, addr: u64) -> Option<String> {
+    let mut bytes = vec![];
+    let word = std::mem::size_of::<libc::c_long>() as u64;
+
+    for offset in 0..512 {
+        *libc::__errno_location() = 0;
+        let data = libc::ptrace(libc::PTRACE_PEEKDATA, pid, addr + offset * word, 0);
+        if data == -1 && *libc::__errno_location() != 0 {
+            return None;
+        }
+
+        for byte in data.to_ne_bytes() {
+            if byte == 0 {
+                return Some(String::from_utf8_lossy(&bytes).to_string());
+            }
+            bytes.push(byte);
+        }
+    }
+    None
+}
+
+#[test]
+fn test_normalize() {
+    #[rustfmt::skip]
+    let cases = [
+        ("/a/b/./c", "/a/b/c"),
+        ("/a/b/../c", "/a/c"),
+        ("/a/./b/../../c/d", "/c/d"),
+    ];
+
+    for (case, correct) in cases {
+        assert_eq!(normalize(Path::new(case)), PathBuf::from(correct));
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_trace() {
+    // the tracer waits on any child, so it runs in a process of its own rather than beside
+    // other tests, which may be waiting on theirs
+    if std::env::var_os("HAYMAKER_TRACE_TEST").is_none() {
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["trace::test_trace", "--exact", "--quiet"])
+            .env("HAYMAKER_TRACE_TEST", "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("haymaker-trace-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("input.txt"), "traced").unwrap();
+    std::fs::copy("/bin/true", dir.join("tool")).unwrap();
+
+    let (_, log) = command("", Path::new(&dir)).unwrap();
+    assert!(log.is_absolute());
+
+    // the tool is only ever executed, never opened
+    let line = format!("cd {} && cat input.txt > output.txt && ./tool", dir.display());
+    assert_eq!(helper(&log, &line), 0);
+
+    let reads = reads(&log, &dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(reads.contains(&String::from("input.txt")), "{:?}", reads);
+    assert!(reads.contains(&String::from("tool")), "{:?}", reads);
+    assert!(!reads.contains(&String::from("output.txt")), "{:?}", reads);
+}
@@ -0,0 +1,149 @@
+//
+// Haymaker
+//
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Watches files through inotify. Directories are watched rather than the files themselves,
+/// since editors often replace a file instead of writing to it.
+pub struct Watcher {
+    fd: i32,
+    dirs: HashMap<i32, PathBuf>,
+    files: Vec<(PathBuf, String)>, // the directory and name of each watched file
+}
+
+impl Watcher {
+    pub fn new(files: &[String]) -> std::io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut watcher = Watcher {
+            fd,
+            dirs: HashMap::new(),
+            files: vec![],
+        };
+
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_ATTRIB
+            | libc::IN_MOVED_TO
+            | libc::IN_CREATE
+            | libc::IN_DELETE;
+
+        for file in files {
+            let (dir, name) = split(Path::new(file));
+            watcher.files.push((dir.clone(), name));
+
+            if watcher.dirs.values().any(|watched| watched == &dir) {
+                continue;
+            }
+
+            let path = CString::new(dir.as_os_str().as_bytes()).unwrap_or_default();
+            let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) };
+            if wd != -1 {
+                watcher.dirs.insert(wd, dir); // directories that don't exist yet can't change
+            }
+        }
+        Ok(watcher)
+    }
+
+    /// Blocks until a watched file changes, returning every file that changed shortly after
+    pub fn wait(&mut self) -> std::io::Result<Vec<String>> {
+        let mut changed = vec![];
+
+        while changed.is_empty() {
+            self.read(&mut changed)?;
+
+            // editors tend to touch files several times in a row
+            while self.poll(Duration::from_millis(100)) {
+                self.read(&mut changed)?;
+            }
+        }
+        Ok(changed)
+    }
+
+    fn poll(&self, timeout: Duration) -> bool {
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as i32) > 0 }
+    }
+
+    fn read(&self, changed: &mut Vec<String>) -> std::io::Result<()> {
+        let mut buffer = [0_u8; 4096];
+        let len = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        if len == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut offset = 0;
+
+        while offset + header <= len as usize {
+            let event = unsafe {
+                std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event)
+            };
+            let name = &buffer[offset + header..offset + header + event.len as usize];
+            let name = name.split(|&c| c == 0).next().unwrap_or_default();
+            let name = String::from_utf8_lossy(name);
+            offset += header + event.len as usize;
+
+            let dir = match self.dirs.get(&event.wd) {
+                Some(dir) => dir,
+                None => continue,
+            };
+
+            for (watched, file) in &self.files {
+                if watched == dir && file == &name {
+                    let path = match dir == Path::new(".") {
+                        true => file.to_owned(),
+                        false => dir.join(file).to_string_lossy().to_string(),
+                    };
+                    if !changed.contains(&path) {
+                        changed.push(path);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn split(path: &Path) -> (PathBuf, String) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let dir = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    (dir, name)
+}
+
+#[test]
+fn test_watch() {
+    let dir = std::env::temp_dir().join(format!("haymaker-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let watched = dir.join("watched.txt").to_string_lossy().to_string();
+    let ignored = dir.join("ignored.txt").to_string_lossy().to_string();
+    std::fs::write(&watched, "old").unwrap();
+
+    let mut watcher = Watcher::new(std::slice::from_ref(&watched)).unwrap();
+    std::fs::write(&ignored, "new").unwrap();
+    std::fs::write(&watched, "new").unwrap();
+
+    assert_eq!(watcher.wait().unwrap(), vec![watched]);
+    std::fs::remove_dir_all(dir).unwrap();
+}